@@ -0,0 +1,64 @@
+use super::utils::unique_temp_dir;
+use std::{fs, path::PathBuf};
+use tokio::process::Command;
+
+const USERNAME: &str = "GITOPS_ASKPASS_USERNAME";
+const PASSWORD: &str = "GITOPS_ASKPASS_PASSWORD";
+
+// 脚本本身不含凭据，只从环境变量中读取，Git for Windows同样通过sh执行它
+const SCRIPT: &str = r#"#!/bin/sh
+case "$1" in
+    Username*) printf '%s\n' "$GITOPS_ASKPASS_USERNAME" ;;
+    *) printf '%s\n' "$GITOPS_ASKPASS_PASSWORD" ;;
+esac
+"#;
+
+// 通过GIT_ASKPASS把token交给git，避免出现在URL、命令行参数、.git/config与日志中
+pub struct Askpass {
+    dir: PathBuf,
+    username: String,
+    password: String,
+}
+
+impl Askpass {
+    pub fn new(username: &str, password: &str) -> Result<Self, anyhow::Error> {
+        let dir = unique_temp_dir("gitops-askpass")?;
+
+        fs::create_dir(&dir)?;
+        let askpass = Self {
+            dir,
+            username: username.into(),
+            password: password.into(),
+        };
+        fs::write(askpass.script(), SCRIPT)?;
+
+        #[cfg(not(windows))]
+        {
+            use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+            fs::set_permissions(&askpass.dir, Permissions::from_mode(0o700))?;
+            fs::set_permissions(askpass.script(), Permissions::from_mode(0o700))?;
+        }
+
+        Ok(askpass)
+    }
+
+    fn script(&self) -> PathBuf {
+        self.dir.join("askpass.sh")
+    }
+
+    // 清空credential.helper，避免全局配置的store等把token写到磁盘上；-c须在子命令之前
+    pub fn apply<'a>(&self, cmd: &'a mut Command) -> &'a mut Command {
+        cmd.arg("-c")
+            .arg("credential.helper=")
+            .env("GIT_ASKPASS", self.script())
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env(USERNAME, &self.username)
+            .env(PASSWORD, &self.password)
+    }
+}
+
+impl Drop for Askpass {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env::consts};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Os {
+    Linux,
+    #[serde(alias = "macos")]
+    Darwin,
+    Windows,
+}
+
+impl Os {
+    fn current() -> Result<Self, anyhow::Error> {
+        match consts::OS {
+            "linux" => Ok(Self::Linux),
+            "macos" => Ok(Self::Darwin),
+            "windows" => Ok(Self::Windows),
+            os => Err(anyhow::anyhow!("不支持的操作系统：{}", os)),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Linux => "linux",
+            Self::Darwin => "darwin",
+            Self::Windows => "windows",
+        }
+    }
+
+    fn archive_ext(&self) -> &'static str {
+        match self {
+            Self::Windows => "zip",
+            _ => "tar.gz",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    #[serde(alias = "x86_64")]
+    Amd64,
+    #[serde(alias = "aarch64")]
+    Arm64,
+    #[serde(alias = "arm")]
+    Armv7,
+}
+
+impl Arch {
+    fn current() -> Result<Self, anyhow::Error> {
+        match consts::ARCH {
+            "x86_64" => Ok(Self::Amd64),
+            "aarch64" => Ok(Self::Arm64),
+            "arm" => Ok(Self::Armv7),
+            arch => Err(anyhow::anyhow!("不支持的CPU架构：{}", arch)),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Amd64 => "amd64",
+            Self::Arm64 => "arm64",
+            Self::Armv7 => "armv7",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Tool {
+    Hugo,
+    Caddy,
+}
+
+impl Tool {
+    // 各工具发布文件的命名规则不同，这里统一映射
+    fn asset_name(&self, version: &str, os: Os, arch: Arch) -> Result<String, anyhow::Error> {
+        match self {
+            Self::Hugo => {
+                // extended版只发布了以下平台，linux-arm与windows-arm64只有标准版
+                let (edition, platform) = match (os, arch) {
+                    (Os::Darwin, _) => ("hugo_extended", "darwin-universal"),
+                    (Os::Linux, Arch::Amd64) => ("hugo_extended", "linux-amd64"),
+                    (Os::Linux, Arch::Arm64) => ("hugo_extended", "linux-arm64"),
+                    (Os::Linux, Arch::Armv7) => ("hugo", "linux-arm"),
+                    (Os::Windows, Arch::Amd64) => ("hugo_extended", "windows-amd64"),
+                    (Os::Windows, Arch::Arm64) => ("hugo", "windows-arm64"),
+                    (Os::Windows, Arch::Armv7) => {
+                        return Err(anyhow::anyhow!("hugo没有发布windows armv7版本！"))
+                    }
+                };
+
+                if edition == "hugo" {
+                    tracing::warn!("hugo没有{}的extended版本，将使用标准版", platform);
+                }
+
+                Ok(format!(
+                    "{}_{}_{}.{}",
+                    edition,
+                    version,
+                    platform,
+                    os.archive_ext()
+                ))
+            }
+            Self::Caddy => {
+                let os_name = match os {
+                    Os::Linux => "linux",
+                    Os::Darwin => "mac",
+                    Os::Windows => "windows",
+                };
+                Ok(format!(
+                    "caddy_{}_{}_{}.{}",
+                    version,
+                    os_name,
+                    arch.as_str(),
+                    os.archive_ext()
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct AssetConfig {
+    os: Option<Os>,
+    arch: Option<Arch>,
+    // 完整文件名模板，支持{version}、{os}、{arch}
+    name: Option<String>,
+    // 按工具自己的命名替换{os}、{arch}，例如dart-sass的amd64为x64
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    os_names: BTreeMap<Os, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    arch_names: BTreeMap<Arch, String>,
+}
+
+impl AssetConfig {
+    // 内置工具按各自的命名规则生成，其他工具只能使用name模板
+    pub fn resolve(&self, tool: Option<Tool>, version: &str) -> Result<String, anyhow::Error> {
+        match (&self.name, tool) {
+            (Some(name), _) => self.render(name, version),
+            (None, Some(tool)) => {
+                let (os, arch) = self.platform()?;
+                tool.asset_name(version, os, arch)
+            }
+            (None, None) => Err(anyhow::anyhow!("未配置asset.name！")),
+        }
+    }
+
+    pub fn render(&self, template: &str, version: &str) -> Result<String, anyhow::Error> {
+        let (os, arch) = self.platform()?;
+        let os = self.os_names.get(&os).map_or(os.as_str(), String::as_str);
+        let arch = self
+            .arch_names
+            .get(&arch)
+            .map_or(arch.as_str(), String::as_str);
+
+        Ok(template
+            .replace("{version}", version)
+            .replace("{os}", os)
+            .replace("{arch}", arch))
+    }
+
+    fn platform(&self) -> Result<(Os, Arch), anyhow::Error> {
+        let os = match self.os {
+            Some(os) => os,
+            None => Os::current()?,
+        };
+        let arch = match self.arch {
+            Some(arch) => arch,
+            None => Arch::current()?,
+        };
+
+        tracing::debug!("目标平台：{:?} {:?}", os, arch);
+        Ok((os, arch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hugo_falls_back_to_standard_edition() {
+        let name = |os, arch| Tool::Hugo.asset_name("0.136.0", os, arch);
+
+        assert_eq!(
+            name(Os::Linux, Arch::Amd64).unwrap(),
+            "hugo_extended_0.136.0_linux-amd64.tar.gz"
+        );
+        assert_eq!(
+            name(Os::Linux, Arch::Armv7).unwrap(),
+            "hugo_0.136.0_linux-arm.tar.gz"
+        );
+        assert_eq!(
+            name(Os::Windows, Arch::Arm64).unwrap(),
+            "hugo_0.136.0_windows-arm64.zip"
+        );
+        assert!(name(Os::Windows, Arch::Armv7).is_err());
+    }
+
+    #[test]
+    fn renders_tool_specific_names() {
+        let asset: AssetConfig = toml::from_str(
+            r#"
+            os = "linux"
+            arch = "x86_64"
+            name = "pagefind-v{version}-{arch}-{os}.tar.gz"
+            os_names = { linux = "unknown-linux-musl" }
+            arch_names = { amd64 = "x86_64" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            asset.resolve(None, "1.1.1").unwrap(),
+            "pagefind-v1.1.1-x86_64-unknown-linux-musl.tar.gz"
+        );
+    }
+}
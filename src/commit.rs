@@ -0,0 +1,103 @@
+use std::{ffi::OsStr, path::Path};
+use tokio::process::Command;
+
+// 提交信息模板支持的占位符：
+// {sha}、{short_sha}、{branch}：源仓库当前的提交与分支
+// {timestamp}、{env}：部署时间与环境名
+// {files}、{added}、{modified}、{deleted}：本次提交暂存的文件变更数
+pub struct CommitInfo {
+    sha: Option<String>,
+    branch: Option<String>,
+    timestamp: String,
+    env: String,
+    changes: Option<FileChanges>,
+}
+
+#[derive(Default)]
+struct FileChanges {
+    files: usize,
+    added: usize,
+    modified: usize,
+    deleted: usize,
+}
+
+impl CommitInfo {
+    pub async fn new(source: &Path, env: &str) -> Self {
+        Self {
+            sha: source_commit(source).await,
+            // 分离HEAD时rev-parse会输出"HEAD"，视为未知
+            branch: git_output(source, ["rev-parse", "--abbrev-ref", "HEAD"])
+                .await
+                .filter(|b| b != "HEAD"),
+            timestamp: chrono::Local::now().to_rfc3339(),
+            env: env.into(),
+            changes: None,
+        }
+    }
+
+    // 需在git add之后调用
+    pub async fn count_staged(&mut self, repo: &Path) -> Result<(), anyhow::Error> {
+        let status = git_output(repo, ["diff", "--cached", "--name-status"])
+            .await
+            .ok_or(anyhow::anyhow!("无法获取暂存区的文件变更！"))?;
+        let mut changes = FileChanges::default();
+
+        for line in status.lines().filter(|l| !l.is_empty()) {
+            changes.files += 1;
+            match &line[..1] {
+                "A" => changes.added += 1,
+                "D" => changes.deleted += 1,
+                _ => changes.modified += 1,
+            }
+        }
+
+        self.changes = Some(changes);
+        Ok(())
+    }
+
+    pub fn render(&self, template: &str) -> String {
+        let unknown = || "unknown".to_owned();
+        // dry-run时还没有暂存任何文件
+        let count = |f: fn(&FileChanges) -> usize| {
+            self.changes
+                .as_ref()
+                .map_or("?".into(), |c| f(c).to_string())
+        };
+        let sha = self.sha.clone().unwrap_or_else(unknown);
+
+        template
+            .replace("{sha}", &sha)
+            .replace("{short_sha}", &sha[..sha.len().min(7)])
+            .replace("{branch}", &self.branch.clone().unwrap_or_else(unknown))
+            .replace("{timestamp}", &self.timestamp)
+            .replace("{env}", &self.env)
+            .replace("{files}", &count(|c| c.files))
+            .replace("{added}", &count(|c| c.added))
+            .replace("{modified}", &count(|c| c.modified))
+            .replace("{deleted}", &count(|c| c.deleted))
+    }
+}
+
+pub async fn source_commit(source: &Path) -> Option<String> {
+    git_output(source, ["rev-parse", "HEAD"]).await
+}
+
+async fn git_output<I, S>(dir: &Path, args: I) -> Option<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .await
+        .ok()?;
+
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,85 @@
+use flate2::{write::GzEncoder, Compression};
+use opendal::Operator;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Write, path::Path};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Gzip,
+    #[serde(alias = "brotli")]
+    Br,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Br => "br",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Self::Br => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+                encoder.write_all(data)?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    encoding: Encoding,
+    min_size: u64,
+    max_size: u64,
+    extensions: BTreeMap<String, bool>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            encoding: Default::default(),
+            min_size: 1024,
+            max_size: 8 * 1024 * 1024,
+            extensions: Default::default(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    const DEFAULT_EXTENSIONS: [&'static str; 6] = ["html", "css", "js", "json", "svg", "xml"];
+
+    fn is_enabled_for(&self, key: &str) -> bool {
+        let Some(ext) = Path::new(key).extension().and_then(|e| e.to_str()) else {
+            return false;
+        };
+        let ext = ext.to_ascii_lowercase();
+
+        match self.extensions.get(&ext) {
+            Some(enabled) => *enabled,
+            None => Self::DEFAULT_EXTENSIONS.contains(&ext.as_str()),
+        }
+    }
+
+    // 预压缩上传依赖presign，不支持presign的存储直接上传原文件
+    pub fn encoding_for(&self, op: &Operator, key: &str, len: u64) -> Option<Encoding> {
+        if (self.min_size..=self.max_size).contains(&len)
+            && self.is_enabled_for(key)
+            && op.info().full_capability().presign_write
+        {
+            Some(self.encoding)
+        } else {
+            None
+        }
+    }
+}
@@ -1,11 +1,22 @@
+mod askpass;
+mod asset;
+mod commit;
+mod compress;
+mod manifest;
 mod mem_probe;
+mod mirror;
 mod opendal_fs;
 mod ops {
     pub mod caddy;
     pub mod hugo;
     pub mod sync;
 }
+mod release;
+mod storage;
+mod tool;
+mod tool_cache;
 mod utils;
+mod version;
 
 use clap::{Parser, Subcommand, ValueEnum};
 use mem_probe::MemProbe;
@@ -16,8 +27,16 @@ use ops::{
 };
 use pushover_rs::{send_pushover_request, PushoverSound};
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{
+    collections::BTreeMap,
+    env::current_dir,
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use storage::StorageConfig;
 use tokio::fs;
+use tool::ToolConfig;
 use tracing_subscriber::fmt::{format::FmtSpan, time::ChronoLocal};
 use utils::env_var;
 
@@ -42,14 +61,28 @@ async fn main() -> Result<(), anyhow::Error> {
         .await?;
 
     match op {
-        Op::Upgrade(Target::Hugo) => Hugo::upgrade(&config).await.map(|_| ()),
-        Op::Upgrade(Target::Caddy) => caddy::upgrade(&config).await,
+        Op::Upgrade { target, rollback } => {
+            match (target, rollback) {
+                (UpgradeTarget::Hugo, false) => Hugo::upgrade(&config).await.map(|_| ()),
+                (UpgradeTarget::Hugo, true) => Hugo::rollback(&config).await,
+                (UpgradeTarget::Caddy, false) => caddy::upgrade(&config).await,
+                (UpgradeTarget::Caddy, true) => caddy::rollback(&config).await,
+                (UpgradeTarget::Tool { name }, false) => {
+                    tool::upgrade(&config, name.as_deref()).await
+                }
+                (UpgradeTarget::Tool { name }, true) => {
+                    tool::rollback(&config, name.as_deref()).await
+                }
+            }
+            .hook_err(&pushover)
+            .await
+        }
         Op::Deploy(target) => {
             let mp = MemProbe::new();
 
             match target {
-                Target::Hugo => hugo::deploy(&config).await,
-                Target::Caddy => caddy::deploy(&config).await,
+                DeployTarget::Hugo { env } => hugo::deploy(&config, &current_dir()?, env).await,
+                DeployTarget::Caddy => caddy::deploy(&config).await,
             }
             .hook_err(&pushover)
             .await?;
@@ -62,7 +95,12 @@ async fn main() -> Result<(), anyhow::Error> {
                 )
                 .await
         }
-        Op::Sync => sync::sync(&config).await,
+        Op::Sync { watch, interval } => sync::sync(&config, *watch, *interval).await,
+        Op::Manifest(ManifestOp::Show { target, env }) => match target {
+            Target::Hugo => hugo::show_manifest(&config, env).await,
+            Target::Caddy => caddy::show_manifest(&config).await,
+        },
+        Op::Rollback { env, to } => hugo::rollback(&config, env, to.as_deref()).await,
         Op::Alarm { reason, host } => {
             pushover
                 .send_if_some(
@@ -106,6 +144,8 @@ struct Cli {
     op: Op,
     #[arg(long, global = true, default_value = "gitops.toml")]
     config: String,
+    #[arg(long, global = true)]
+    dry_run: bool,
 }
 
 impl Cli {
@@ -113,15 +153,27 @@ impl Cli {
         let s = Self::parse();
         tracing::info!("config: {:?}", s.config);
         tracing::info!("op: {:?}", s.op);
+        if s.dry_run {
+            tracing::info!("dry-run模式：仅打印执行计划");
+        }
         s
     }
 
     fn get_pushover(&self) -> Result<Pushover, anyhow::Error> {
-        if self.op.need_pushover() {
+        let pushover = || {
             Ok(Pushover::Some {
                 user_key: env_var("PUSHOVER_USER_KEY")?,
                 app_token: env_var("PUSHOVER_APP_TOKEN")?,
             })
+        };
+
+        if self.dry_run {
+            Ok(Pushover::None)
+        } else if self.op.need_pushover() {
+            pushover()
+        } else if self.op.is_upgrade() {
+            // upgrade失败时尽量通知，但不强制要求配置Pushover
+            Ok(pushover().unwrap_or(Pushover::None))
         } else {
             Ok(Pushover::None)
         }
@@ -130,7 +182,11 @@ impl Cli {
     async fn resolve_config(&self) -> Result<Config, anyhow::Error> {
         let config = &self.config;
         tracing::info!("正在读取{}……", config);
-        Ok(toml::from_str(&fs::read_to_string(config).await?)?)
+        Ok(Config {
+            dry_run: self.dry_run,
+            lock_file: Path::new(config).with_extension("lock"),
+            ..toml::from_str(&fs::read_to_string(config).await?)?
+        })
     }
 }
 
@@ -142,11 +198,28 @@ impl Drop for Cli {
 
 #[derive(Subcommand, Debug)]
 enum Op {
+    Upgrade {
+        #[command(subcommand)]
+        target: UpgradeTarget,
+        #[arg(long, global = true)]
+        rollback: bool,
+    },
     #[command(subcommand)]
-    Upgrade(Target),
+    Deploy(DeployTarget),
+    Sync {
+        #[arg(long)]
+        watch: bool,
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "60s")]
+        interval: Duration,
+    },
+    Rollback {
+        #[arg(long, default_value = "prod")]
+        env: String,
+        #[arg(long)]
+        to: Option<String>,
+    },
     #[command(subcommand)]
-    Deploy(Target),
-    Sync,
+    Manifest(ManifestOp),
     Alarm {
         reason: Alarm,
         host: String,
@@ -155,19 +228,42 @@ enum Op {
 
 impl Op {
     fn need_pushover(&self) -> bool {
-        match self {
-            Self::Deploy(_) | Self::Alarm { reason: _, host: _ } => true,
-            _ => false,
-        }
+        matches!(self, Self::Deploy(_) | Self::Alarm { reason: _, host: _ })
     }
 
     fn is_deploy(&self) -> bool {
-        if let Self::Deploy(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Self::Deploy(_))
     }
+
+    fn is_upgrade(&self) -> bool {
+        matches!(self, Self::Upgrade { .. })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum ManifestOp {
+    Show {
+        #[command(subcommand)]
+        target: Target,
+        #[arg(long, default_value = "prod")]
+        env: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UpgradeTarget {
+    Hugo,
+    Caddy,
+    Tool { name: Option<String> },
+}
+
+#[derive(Subcommand, Debug)]
+enum DeployTarget {
+    Hugo {
+        #[arg(long)]
+        env: Vec<String>,
+    },
+    Caddy,
 }
 
 #[derive(Subcommand, Debug)]
@@ -224,6 +320,13 @@ struct Config {
     hugo: Option<HugoConfig>,
     caddy: Option<CaddyConfig>,
     sync: Option<SyncConfig>,
+    storage: Option<StorageConfig>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tools: BTreeMap<String, ToolConfig>,
+    #[serde(skip)]
+    dry_run: bool,
+    #[serde(skip)]
+    lock_file: PathBuf,
 }
 
 trait HookErrIf<T>: Sized {
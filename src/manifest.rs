@@ -0,0 +1,92 @@
+use super::{commit::source_commit, opendal_fs::path_to_key};
+use opendal::Operator;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::fs;
+
+pub const MANIFEST: &str = ".gitops-manifest.json";
+
+#[derive(Deserialize, Serialize)]
+pub struct Manifest {
+    deploy_id: String,
+    source_commit: Option<String>,
+    created_at: String,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+    content_type: String,
+}
+
+impl Manifest {
+    pub async fn new(deploy_id: &str, source: &Path) -> Self {
+        Self {
+            deploy_id: deploy_id.into(),
+            source_commit: source_commit(source).await,
+            created_at: chrono::Local::now().to_rfc3339(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, path: &str, data: impl AsRef<[u8]>) {
+        let data = data.as_ref();
+        self.files.push(ManifestEntry {
+            path: path.into(),
+            size: data.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(data)),
+            content_type: mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+        });
+    }
+
+    // 清单中记录相对于base的路径
+    pub async fn push_file(
+        &mut self,
+        base: &Path,
+        path: impl AsRef<Path>,
+    ) -> Result<(), anyhow::Error> {
+        let path = base.join(path);
+        self.push(
+            &path_to_key(path.strip_prefix(base)?)?,
+            fs::read(&path).await?,
+        );
+        Ok(())
+    }
+
+    pub async fn write(&mut self, op: &Operator) -> Result<(), anyhow::Error> {
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        tracing::info!("正在上传：{}（共{}个文件）", MANIFEST, self.files.len());
+        Ok(op
+            .write_with(MANIFEST, serde_json::to_vec_pretty(self)?)
+            .content_type("application/json")
+            .await?)
+    }
+
+    pub async fn read(op: &Operator) -> Result<Self, anyhow::Error> {
+        Ok(serde_json::from_slice(&op.read(MANIFEST).await?.to_vec())?)
+    }
+
+    pub fn print(&self) {
+        println!("deploy id: {}", self.deploy_id);
+        println!(
+            "source commit: {}",
+            self.source_commit.as_deref().unwrap_or("-")
+        );
+        println!("created at: {}", self.created_at);
+        println!("files: {}", self.files.len());
+
+        for f in &self.files {
+            println!(
+                "{}  {:>10}  {:<24}  {}",
+                f.sha256, f.size, f.content_type, f.path
+            );
+        }
+    }
+}
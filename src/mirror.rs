@@ -0,0 +1,168 @@
+use super::opendal_fs::{collect_files_blocking, compile_glob, path_to_key};
+use globset::GlobMatcher;
+use std::{collections::HashSet, fs, path::Path};
+use tokio::task::spawn_blocking;
+use walkdir::WalkDir;
+
+#[derive(Clone)]
+pub struct KeepList(Vec<GlobMatcher>);
+
+impl KeepList {
+    pub fn new(patterns: &[String]) -> Result<Self, anyhow::Error> {
+        Ok(Self(
+            patterns
+                .iter()
+                .map(|p| compile_glob(p))
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        self.0.iter().any(|m| m.is_match(key))
+    }
+}
+
+#[derive(Default)]
+pub struct MirrorStats {
+    pub copied: usize,
+    pub deleted: usize,
+    pub kept: usize,
+}
+
+// 把src的内容同步到dst：exact为true时删除dst中多余的文件，keep中的文件既不删除也不覆盖，.git始终保留
+pub fn mirror_blocking(
+    src: &Path,
+    dst: &Path,
+    exact: bool,
+    keep: &KeepList,
+) -> Result<MirrorStats, anyhow::Error> {
+    let mut stats = MirrorStats::default();
+    let files = collect_files_blocking(src)?
+        .into_iter()
+        .map(|f| Ok((path_to_key(f.strip_prefix(src)?)?, f)))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    if exact && dst.is_dir() {
+        let keys = files
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect::<HashSet<_>>();
+        let mut dirs = Vec::new();
+        // contents_first会让filter_entry无法跳过目录，所以空目录放到最后倒序删除
+        let walker = WalkDir::new(dst)
+            .min_depth(1)
+            .into_iter()
+            .filter_entry(|e| e.file_name() != ".git");
+
+        for entry in walker {
+            let entry = entry?;
+            let path = entry.path();
+
+            if entry.file_type().is_dir() {
+                dirs.push(path.to_owned());
+                continue;
+            }
+
+            let key = path_to_key(path.strip_prefix(dst)?)?;
+
+            if keys.contains(key.as_str()) {
+                continue;
+            } else if keep.matches(&key) {
+                stats.kept += 1;
+            } else {
+                tracing::debug!("正在删除：{}", key);
+                fs::remove_file(path)?;
+                stats.deleted += 1;
+            }
+        }
+
+        // 只删除空目录，非空时忽略错误
+        for dir in dirs.iter().rev() {
+            fs::remove_dir(dir).ok();
+        }
+    }
+
+    for (key, f) in &files {
+        let target = dst.join(key);
+
+        if keep.matches(key) && target.exists() {
+            tracing::info!("保留受保护的文件：{}", key);
+            stats.kept += 1;
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::copy(f, &target)?;
+        stats.copied += 1;
+    }
+
+    Ok(stats)
+}
+
+pub async fn mirror(
+    src: &Path,
+    dst: &Path,
+    exact: bool,
+    keep: &KeepList,
+) -> Result<MirrorStats, anyhow::Error> {
+    let (src, dst, keep) = (src.to_owned(), dst.to_owned(), keep.clone());
+    spawn_blocking(move || mirror_blocking(&src, &dst, exact, &keep)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn mirror_removes_stale_files_but_keeps_git_and_protected_files() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let (src, dst) = (src.path(), dst.path());
+
+        write(&src.join("index.html"), "new");
+        write(&src.join("posts/a.html"), "a");
+        write(&src.join("CNAME"), "example.org");
+        write(&dst.join(".git/config"), "[core]");
+        write(&dst.join("index.html"), "old");
+        write(&dst.join("stale.html"), "stale");
+        write(&dst.join("old/b.html"), "b");
+        write(&dst.join("CNAME"), "keep.example.org");
+
+        let keep = KeepList::new(&["CNAME".into()]).unwrap();
+        let stats = mirror_blocking(src, dst, true, &keep).unwrap();
+
+        assert_eq!((stats.copied, stats.deleted, stats.kept), (2, 2, 1));
+        assert_eq!(fs::read_to_string(dst.join("index.html")).unwrap(), "new");
+        assert_eq!(fs::read_to_string(dst.join("posts/a.html")).unwrap(), "a");
+        assert_eq!(
+            fs::read_to_string(dst.join("CNAME")).unwrap(),
+            "keep.example.org"
+        );
+        assert!(dst.join(".git/config").is_file());
+        assert!(!dst.join("stale.html").exists());
+        assert!(!dst.join("old").exists());
+    }
+
+    #[test]
+    fn copy_mode_leaves_extra_files() {
+        let src = tempfile::tempdir().unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        let (src, dst) = (src.path(), dst.path());
+
+        write(&src.join("index.html"), "new");
+        write(&dst.join("stale.html"), "stale");
+
+        let stats = mirror_blocking(src, dst, false, &KeepList::new(&[]).unwrap()).unwrap();
+
+        assert_eq!((stats.copied, stats.deleted), (1, 0));
+        assert!(dst.join("stale.html").is_file());
+    }
+}
@@ -1,80 +1,461 @@
-use opendal::Operator;
+use super::{
+    compress::{CompressionConfig, Encoding},
+    storage::StorageConfig,
+    utils::{dry_run, retain_decimal_places},
+};
+use backon::{ExponentialBuilder, Retryable};
+use globset::{GlobBuilder, GlobMatcher};
+use md5::{Digest, Md5};
+use opendal::{Entry, Metadata, Metakey, Operator};
+use reqwest::{header::CONTENT_ENCODING, Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use std::{
-    io,
+    collections::{BTreeMap, HashMap},
     path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    time::Duration,
 };
 use tokio::{
     fs,
+    io::AsyncReadExt,
+    sync::Semaphore,
     task::{spawn_blocking, JoinHandle},
 };
 use walkdir::WalkDir;
 
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct UploadConfig {
+    max_in_flight: usize,
+    multipart_threshold: u64,
+    chunk_size: usize,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 16,
+            multipart_threshold: 16 * 1024 * 1024,
+            chunk_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl UploadConfig {
+    // chunk_size为0时会把大文件写成空对象，过小时服务端会拒绝分片
+    pub fn validate(&self, min_chunk_size: usize) -> Result<(), anyhow::Error> {
+        let min_chunk_size = min_chunk_size.max(1);
+
+        if self.chunk_size < min_chunk_size {
+            return Err(anyhow::anyhow!(
+                "upload.chunk_size为{}，不能小于{}字节！",
+                self.chunk_size,
+                min_chunk_size
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// 所有预压缩上传共用一个连接池
+static HTTP_CLIENT: LazyLock<Client> = LazyLock::new(Client::new);
+
+// 分片上传的ETag不是内容的MD5，另外把MD5写入自定义元数据
+pub const CONTENT_MD5: &str = "gitops-md5";
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ObjectRule {
+    pattern: String,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Default)]
+pub struct ObjectRules(Arc<Vec<(GlobMatcher, ObjectRule)>>);
+
+#[derive(Clone, Default)]
+struct ObjectMeta {
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl ObjectRules {
+    pub fn new(rules: &[ObjectRule]) -> Result<Self, anyhow::Error> {
+        Ok(Self(Arc::new(
+            rules
+                .iter()
+                .map(|r| Ok((compile_glob(&r.pattern)?, r.clone())))
+                .collect::<Result<Vec<_>, anyhow::Error>>()?,
+        )))
+    }
+
+    // 按顺序合并所有匹配的规则，靠后的规则优先
+    fn resolve(&self, op: &Operator, key: &str) -> ObjectMeta {
+        let cap = op.info().full_capability();
+        let mut meta = ObjectMeta::default();
+
+        for (matcher, rule) in self.0.iter().filter(|(m, _)| m.is_match(key)) {
+            if rule.cache_control.is_some() {
+                meta.cache_control.clone_from(&rule.cache_control);
+            }
+
+            if rule.content_disposition.is_some() {
+                meta.content_disposition
+                    .clone_from(&rule.content_disposition);
+            }
+
+            meta.metadata.extend(rule.metadata.clone());
+            tracing::debug!("{}匹配规则：{}", key, matcher.glob());
+        }
+
+        if !cap.write_with_cache_control && meta.cache_control.take().is_some() {
+            tracing::warn!("存储不支持Cache-Control，已忽略：{}", key);
+        }
+
+        if !cap.write_with_content_disposition && meta.content_disposition.take().is_some() {
+            tracing::warn!("存储不支持Content-Disposition，已忽略：{}", key);
+        }
+
+        if !cap.write_with_user_metadata && !meta.metadata.is_empty() {
+            meta.metadata.clear();
+            tracing::warn!("存储不支持自定义元数据，已忽略：{}", key);
+        }
+
+        meta
+    }
+}
+
+pub fn compile_glob(pattern: &str) -> Result<GlobMatcher, anyhow::Error> {
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
 pub struct ConcurrentUploadTasks {
     op: Operator,
-    handles: Vec<JoinHandle<Result<(), opendal::Error>>>,
+    config: Arc<UploadConfig>,
+    backoff: ExponentialBuilder,
+    rules: ObjectRules,
+    compression: Option<Arc<CompressionConfig>>,
+    in_flight: Arc<Semaphore>,
+    handles: Vec<(String, JoinHandle<Result<(), anyhow::Error>>)>,
 }
 
 impl ConcurrentUploadTasks {
-    pub fn new(op: Operator) -> Self {
+    pub fn new(op: Operator, storage: &StorageConfig) -> Self {
+        let config = storage.upload();
         Self {
             op,
+            config: Arc::new(config.clone()),
+            backoff: storage.retry().backoff(),
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight.max(1))),
+            rules: Default::default(),
+            compression: None,
             handles: Vec::new(),
         }
     }
 
+    pub fn with_rules(mut self, rules: &ObjectRules) -> Self {
+        self.rules = rules.clone();
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Option<&CompressionConfig>) -> Self {
+        self.compression = compression.map(|c| Arc::new(c.clone()));
+        self
+    }
+
     pub async fn push_single_file(
         &mut self,
         src: impl AsRef<Path>,
         target: &str,
-    ) -> Result<(), io::Error> {
-        let data = fs::read(src).await?;
+    ) -> Result<(), anyhow::Error> {
+        let permit = self.in_flight.clone().acquire_owned().await?;
         let op = self.op.clone();
+        let config = self.config.clone();
+        let backoff = self.backoff;
+        let compression = self.compression.clone();
+        let meta = self.rules.resolve(&op, target);
+        let src = src.as_ref().to_owned();
         let target = target.to_owned();
 
-        Ok(self.handles.push(tokio::spawn(async move {
-            tracing::info!("正在上传：{}", target);
-            op.write(&target, data).await
-        })))
-    }
-
-    pub async fn push_path(&mut self, path: impl AsRef<Path>) -> Result<(), anyhow::Error> {
-        let path = path.as_ref();
-        Ok(self
-            .push_single_file(
-                path,
-                &path
-                    .to_str()
-                    .ok_or(anyhow::anyhow!("非法路径！"))?
-                    .replace("\\", "/"),
-            )
-            .await?)
+        self.handles.push((
+            target.clone(),
+            tokio::spawn(async move {
+                let result = upload_file(
+                    &op,
+                    &src,
+                    &target,
+                    &config,
+                    backoff,
+                    compression.as_deref(),
+                    &meta,
+                )
+                .await;
+                drop(permit);
+                result
+            }),
+        ));
+        Ok(())
     }
 
-    pub async fn push_str(&mut self, path: &str) -> Result<(), io::Error> {
+    pub async fn push_str(&mut self, path: &str) -> Result<(), anyhow::Error> {
         self.push_single_file(path, path).await
     }
 
-    pub async fn push_str_seq(&mut self, seq: &Vec<String>) -> Result<(), io::Error> {
+    // seq中的路径相对于base，同时作为上传的key
+    pub async fn push_str_seq(&mut self, base: &Path, seq: &[String]) -> Result<(), anyhow::Error> {
         for path in seq {
-            self.push_str(path).await?;
+            self.push_single_file(base.join(path), path).await?;
         }
         Ok(())
     }
 
+    pub async fn plan_dir(&self, base: &Path, dir: &str) -> Result<DirPlan, anyhow::Error> {
+        tracing::info!("正在加载目录……");
+        let files = collect_files(base.join(dir)).await?;
+
+        tracing::info!("正在列出远端文件……");
+        let mut orphans = list_remote(&self.op, dir).await?;
+        let mut uploads = Vec::new();
+        let mut unchanged = 0;
+
+        for path in files {
+            let key = path_to_key(path.strip_prefix(base)?)?;
+
+            match orphans.remove(&key) {
+                Some(remote)
+                    if is_unchanged(
+                        &self.op,
+                        &path,
+                        &key,
+                        &remote,
+                        self.compression.as_deref(),
+                    )
+                    .await? =>
+                {
+                    unchanged += 1
+                }
+                _ => uploads.push((path, key)),
+            }
+        }
+
+        let mut deletes = orphans.into_keys().collect::<Vec<String>>();
+        deletes.sort();
+
+        Ok(DirPlan {
+            uploads,
+            unchanged,
+            deletes,
+        })
+    }
+
+    pub async fn sync_dir(mut self, base: &Path, dir: &str) -> Result<usize, anyhow::Error> {
+        let plan = self.plan_dir(base, dir).await?;
+        let op = self.op.clone();
+
+        tracing::info!("开始上传……");
+        for (path, key) in &plan.uploads {
+            self.push_single_file(path, key).await?;
+        }
+
+        let uploaded = self.join().await?;
+        tracing::info!("已上传：{}，未变化：{}", uploaded, plan.unchanged);
+
+        if !plan.deletes.is_empty() {
+            tracing::info!("正在删除{}个远端多余文件……", plan.deletes.len());
+            op.remove(plan.deletes).await?;
+        }
+
+        Ok(uploaded)
+    }
+
     pub async fn join(self) -> Result<usize, anyhow::Error> {
         let tasks = self.handles.len();
-        let mut results = Vec::new();
+        let mut failures = Vec::new();
+
+        for (target, h) in self.handles {
+            let result = match h.await {
+                Ok(r) => r,
+                Err(err) => Err(err.into()),
+            };
+
+            if let Err(err) = result {
+                tracing::error!("上传失败：{}：{}", target, err);
+                failures.push(format!("{}：{}", target, err));
+            }
+        }
+
+        if failures.is_empty() {
+            Ok(tasks)
+        } else {
+            Err(anyhow::anyhow!(
+                "共{}个文件，其中{}个上传失败：\r\n{}",
+                tasks,
+                failures.len(),
+                failures.join("\r\n")
+            ))
+        }
+    }
+}
+
+// FutureWrite和FutureWriter没有公共trait，只能用宏复用
+macro_rules! with_meta {
+    ($future:expr, $meta:expr) => {{
+        let mut future = $future;
+
+        if let Some(v) = &$meta.cache_control {
+            future = future.cache_control(v);
+        }
 
-        for h in self.handles {
-            results.push(h.await?);
+        if let Some(v) = &$meta.content_disposition {
+            future = future.content_disposition(v);
         }
 
-        for r in results {
-            r?;
+        if !$meta.metadata.is_empty() {
+            future = future.user_metadata($meta.metadata.clone());
         }
 
-        Ok(tasks)
+        future
+    }};
+}
+
+async fn upload_file(
+    op: &Operator,
+    src: &Path,
+    target: &str,
+    config: &UploadConfig,
+    backoff: ExponentialBuilder,
+    compression: Option<&CompressionConfig>,
+    meta: &ObjectMeta,
+) -> Result<(), anyhow::Error> {
+    let len = fs::metadata(src).await?.len();
+
+    if let Some(encoding) = compression.and_then(|c| c.encoding_for(op, target, len)) {
+        let data = encoding.compress(&fs::read(src).await?)?;
+        return upload_encoded(op, target, data, encoding, backoff, meta).await;
+    }
+
+    if len < config.multipart_threshold {
+        tracing::info!("正在上传：{}", target);
+        return Ok(with_meta!(op.write_with(target, fs::read(src).await?), meta).await?);
     }
+
+    tracing::info!(
+        "正在分片上传：{}（{} MB）",
+        target,
+        retain_decimal_places(len as f64 / 1024.0 / 1024.0, 3)
+    );
+
+    let mut meta = meta.clone();
+    if op.info().full_capability().write_with_user_metadata {
+        meta.metadata
+            .insert(CONTENT_MD5.into(), file_md5(src, config.chunk_size).await?);
+    }
+
+    let mut file = fs::File::open(src).await?;
+    let mut writer = with_meta!(op.writer_with(target).chunk(config.chunk_size), meta).await?;
+
+    let result = async {
+        loop {
+            let mut chunk = Vec::with_capacity(config.chunk_size);
+            (&mut file)
+                .take(config.chunk_size as u64)
+                .read_to_end(&mut chunk)
+                .await?;
+
+            if chunk.is_empty() {
+                break;
+            }
+
+            writer.write(chunk).await?;
+        }
+
+        Ok::<(), anyhow::Error>(writer.close().await?)
+    }
+    .await;
+
+    if result.is_err() {
+        writer.abort().await.ok();
+    }
+
+    result
+}
+
+async fn file_md5(src: &Path, chunk_size: usize) -> Result<String, anyhow::Error> {
+    let mut file = fs::File::open(src).await?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0; chunk_size];
+
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// opendal不支持设置Content-Encoding，只能通过presign的请求自行上传，
+// 因此不经过RetryLayer，需要按相同的策略自行重试
+async fn upload_encoded(
+    op: &Operator,
+    target: &str,
+    data: Vec<u8>,
+    encoding: Encoding,
+    backoff: ExponentialBuilder,
+    meta: &ObjectMeta,
+) -> Result<(), anyhow::Error> {
+    tracing::info!("正在上传{}压缩的：{}", encoding.as_str(), target);
+
+    if !meta.metadata.is_empty() {
+        tracing::warn!("预压缩上传不支持自定义元数据，已忽略：{}", target);
+    }
+
+    let mut presign = op
+        .presign_write_with(target, Duration::from_secs(3600))
+        .content_type(
+            mime_guess::from_path(target)
+                .first_or_octet_stream()
+                .as_ref(),
+        );
+
+    if let Some(v) = &meta.cache_control {
+        presign = presign.cache_control(v);
+    }
+
+    if let Some(v) = &meta.content_disposition {
+        presign = presign.content_disposition(v);
+    }
+
+    let req = presign.await?;
+    let put = || async {
+        HTTP_CLIENT
+            .request(req.method().clone(), req.uri().to_string())
+            .headers(req.header().clone())
+            .header(CONTENT_ENCODING, encoding.as_str())
+            .body(data.clone())
+            .send()
+            .await?
+            .error_for_status()
+    };
+
+    put.retry(backoff)
+        .when(|err| {
+            err.status()
+                .is_none_or(|s| s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS)
+        })
+        .notify(|err, dur| tracing::warn!("操作失败，将在{:?}后重试：{}", dur, err))
+        .await?;
+
+    Ok(())
 }
 
 pub fn collect_files_blocking(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, anyhow::Error> {
@@ -91,24 +472,193 @@ pub fn collect_files_blocking(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, any
     Ok(files)
 }
 
-pub async fn collect_files(dir: &str) -> Result<Vec<PathBuf>, anyhow::Error> {
-    let dir = dir.to_owned();
+pub async fn collect_files(dir: impl AsRef<Path>) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let dir = dir.as_ref().to_owned();
     spawn_blocking(move || collect_files_blocking(dir)).await?
 }
 
-pub async fn sync_dir(op: &Operator, dir: &str) -> Result<usize, anyhow::Error> {
-    tracing::info!("正在加载目录……");
-    let files = collect_files(dir).await?;
+pub fn path_to_key(path: impl AsRef<Path>) -> Result<String, anyhow::Error> {
+    Ok(path
+        .as_ref()
+        .to_str()
+        .ok_or(anyhow::anyhow!("非法路径！"))?
+        .replace("\\", "/"))
+}
+
+pub fn md5_hex(data: impl AsRef<[u8]>) -> String {
+    format!("{:x}", Md5::digest(data))
+}
+
+pub async fn list_remote(
+    op: &Operator,
+    dir: &str,
+) -> Result<HashMap<String, Metadata>, anyhow::Error> {
+    let entries = op
+        .list_with(&format!("{}/", dir.trim_end_matches('/')))
+        .recursive(true)
+        .metakey(Metakey::ContentLength | Metakey::Etag)
+        .await?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|e| e.metadata().is_file())
+        .map(Entry::into_parts)
+        .collect())
+}
+
+async fn is_unchanged(
+    op: &Operator,
+    path: &Path,
+    key: &str,
+    remote: &Metadata,
+    compression: Option<&CompressionConfig>,
+) -> Result<bool, anyhow::Error> {
+    let len = fs::metadata(path).await?.len();
+    let local = match compression.and_then(|c| c.encoding_for(op, key, len)) {
+        Some(encoding) => encoding.compress(&fs::read(path).await?)?,
+        None if len != remote.content_length() => return Ok(false),
+        None => fs::read(path).await?,
+    };
+
+    if local.len() as u64 != remote.content_length() {
+        return Ok(false);
+    }
+
+    let local = md5_hex(local);
+
+    Ok(match remote.etag().map(|etag| etag.trim_matches('"')) {
+        // 分片上传的ETag形如<hash>-<分片数>，改为比较上传时写入的MD5，没有时视为已变化
+        Some(etag) if etag.contains('-') => op
+            .stat(key)
+            .await?
+            .user_metadata()
+            .and_then(|m| m.get(CONTENT_MD5))
+            .is_some_and(|md5| md5.eq_ignore_ascii_case(&local)),
+        Some(etag) => etag.eq_ignore_ascii_case(&local),
+        None => md5_hex(op.read(key).await?.to_bytes()) == local,
+    })
+}
+
+pub struct DirPlan {
+    pub uploads: Vec<(PathBuf, String)>,
+    pub unchanged: usize,
+    pub deletes: Vec<String>,
+}
+
+impl DirPlan {
+    pub fn print(&self) {
+        for (_, key) in &self.uploads {
+            dry_run(format!("上传：{}", key));
+        }
+
+        for key in &self.deletes {
+            dry_run(format!("删除：{}", key));
+        }
 
-    tracing::info!("正在删除旧target……");
-    op.remove_all(dir).await?;
+        dry_run(format!(
+            "共上传{}个，删除{}个，未变化{}个",
+            self.uploads.len(),
+            self.deletes.len(),
+            self.unchanged
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(config: &str) -> StorageConfig {
+        StorageConfig::resolve(&Some(toml::from_str(config).unwrap())).unwrap()
+    }
+
+    async fn write_local(base: &Path, files: &[(&str, &str)]) {
+        for (key, contents) in files {
+            let path = base.join(key);
+            fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            fs::write(path, contents).await.unwrap();
+        }
+    }
+
+    async fn seed(op: &Operator) {
+        op.write("posts/same.html", "same").await.unwrap();
+        op.write("posts/changed.html", "old").await.unwrap();
+        op.write("posts/orphan.html", "orphan").await.unwrap();
+        op.write("index.html", "outside").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_dir_uploads_changes_and_deletes_orphans() {
+        let local = tempfile::tempdir().unwrap();
+        write_local(
+            local.path(),
+            &[
+                ("posts/same.html", "same"),
+                ("posts/changed.html", "new"),
+                ("posts/added.html", "added"),
+            ],
+        )
+        .await;
+
+        let storage = storage(r#"kind = "memory""#);
+        let op = storage.operator("site", None).unwrap();
+        seed(&op).await;
+
+        let tasks = || ConcurrentUploadTasks::new(op.clone(), &storage);
+        let plan = tasks().plan_dir(local.path(), "posts").await.unwrap();
+        let mut uploads = plan
+            .uploads
+            .iter()
+            .map(|(_, key)| key.as_str())
+            .collect::<Vec<_>>();
+        uploads.sort();
+
+        assert_eq!(uploads, ["posts/added.html", "posts/changed.html"]);
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.deletes, ["posts/orphan.html"]);
 
-    tracing::info!("开始上传……");
-    let mut upload = ConcurrentUploadTasks::new(op.clone());
+        let uploaded = tasks().sync_dir(local.path(), "posts").await.unwrap();
+        let read = |key: &'static str| {
+            let op = op.clone();
+            async move { op.read(key).await.unwrap().to_vec() }
+        };
 
-    for path in files {
-        upload.push_path(path).await?;
+        assert_eq!(uploaded, 2);
+        assert_eq!(read("posts/changed.html").await, b"new");
+        assert_eq!(read("posts/added.html").await, b"added");
+        assert_eq!(read("posts/same.html").await, b"same");
+        assert!(!op.is_exist("posts/orphan.html").await.unwrap());
+        // 只同步posts目录，其他远端文件不受影响
+        assert_eq!(read("index.html").await, b"outside");
     }
 
-    upload.join().await
+    #[tokio::test]
+    async fn sync_dir_keeps_orphans_when_an_upload_fails() {
+        let local = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        write_local(
+            local.path(),
+            &[("posts/changed.html", "new"), ("posts/added.html", "added")],
+        )
+        .await;
+
+        let storage = storage(&format!(
+            "kind = \"fs\"\nroot = {:?}\n[retry]\nmax_attempts = 1",
+            store.path().to_str().unwrap()
+        ));
+        let op = storage.operator("site", None).unwrap();
+        seed(&op).await;
+        // 远端同名目录让added.html写入失败
+        fs::create_dir_all(store.path().join("site/posts/added.html"))
+            .await
+            .unwrap();
+
+        let result = ConcurrentUploadTasks::new(op.clone(), &storage)
+            .sync_dir(local.path(), "posts")
+            .await;
+
+        assert!(result.unwrap_err().to_string().contains("posts/added.html"));
+        assert!(op.is_exist("posts/orphan.html").await.unwrap());
+        assert!(op.is_exist("posts/same.html").await.unwrap());
+    }
 }
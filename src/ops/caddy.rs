@@ -1,18 +1,25 @@
 use super::super::{
-    utils::{env_var, retain_decimal_places, spawn_command, unzip},
-    Config,
+    asset::Tool,
+    commit::CommitInfo,
+    manifest::{Manifest, MANIFEST},
+    release::Releases,
+    storage::StorageConfig,
+    tool::{self, ToolConfig},
+    tool_cache::ToolCache,
+    utils::{dry_run, spawn_command},
+    version, Config,
 };
-use opendal::{layers::MimeGuessLayer, services::Oss, Operator};
 use serde::{Deserialize, Serialize};
-use std::{
-    env::current_exe,
-    path::{Path, PathBuf},
-};
+use similar::TextDiff;
+use std::path::{Path, PathBuf};
 use tokio::{fs, process::Command};
 
+const CADDY_REPO: &str = "caddyserver/caddy";
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct CaddyConfig {
-    version: String,
+    #[serde(flatten)]
+    tool: ToolConfig,
     deploy: Option<DeployConfig>,
     routes: Option<Routes>,
 }
@@ -29,12 +36,20 @@ impl CaddyConfig {
         ))
     }
 
-    fn version(version: &str) -> Self {
+    // 只保留工具配置，版本替换为解析后的精确版本
+    fn pinned(&self, version: &str) -> Self {
         Self {
-            version: version.into(),
+            tool: self.tool.with_version(version),
             ..Default::default()
         }
     }
+
+    fn tool(&self) -> ToolConfig {
+        self.tool.or(ToolConfig::github(
+            CADDY_REPO,
+            "caddy_{version}_checksums.txt",
+        ))
+    }
 }
 
 impl From<CaddyConfig> for Config {
@@ -49,15 +64,20 @@ impl From<CaddyConfig> for Config {
 #[derive(Deserialize, Serialize, Clone)]
 struct DeployConfig {
     oss: OssConfig,
+    // 提交信息模板，占位符见commit模块，{env}固定为ops
+    #[serde(default = "DeployConfig::default_message")]
+    message: String,
+}
+
+impl DeployConfig {
+    fn default_message() -> String {
+        "版本控制生成的Caddyfile".into()
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 struct OssConfig {
     root: String,
-    access_key_id: Option<String>,
-    access_key_secret: Option<String>,
-    ops_bucket: Option<String>,
-    ops_endpoint: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -88,12 +108,14 @@ impl Routes {
 }
 
 fn push_site_blocks<T>(
-    r: &Vec<T>,
+    r: &[T],
     blocks: &mut Vec<String>,
     f: impl Fn(&T) -> Result<String, anyhow::Error>,
 ) -> Result<(), anyhow::Error> {
-    Ok(r.iter()
-        .try_for_each(|r| Ok::<(), anyhow::Error>(blocks.push(f(r)?)))?)
+    r.iter().try_for_each(|r| {
+        blocks.push(f(r)?);
+        Ok(())
+    })
 }
 
 #[derive(Deserialize, Serialize)]
@@ -148,111 +170,76 @@ fn check_addrs(addrs: &[String]) -> Result<(), anyhow::Error> {
 
 #[cfg(not(target_os = "macos"))]
 pub async fn upgrade(config: &Config) -> Result<(), anyhow::Error> {
-    let version = &get_config(config)?.version;
-
-    tracing::info!("请求的caddy版本是：{}", version);
-    tracing::info!("正在校验现有caddy版本……");
+    let tool = get_config(config)?.tool();
+    let caddy = tool.cache("caddy")?.link();
 
-    let exe = current_exe()?;
-    let caddy = exe.with_file_name("caddy");
-    let mut need_fetch = true;
-
-    if let Ok(output) = Command::new(&caddy).arg("version").output().await {
-        let status = output.status;
+    if Command::new(&caddy).arg("version").output().await.is_err() {
+        return Err(anyhow::anyhow!("需要先手动部署一个初始版本的caddy！"));
+    }
 
-        if status.success() {
-            if output
-                .stdout
-                .starts_with(format!("v{}", version).as_bytes())
-            {
-                need_fetch = false;
-                tracing::info!("现有caddy版本匹配！将跳过下载");
-            } else {
-                tracing::info!("现有caddy版本不匹配，准备更新caddy");
-            }
+    if let Some(upgrade) = tool::prepare(config, "caddy", &tool, Some(Tool::Caddy)).await? {
+        if config.dry_run {
+            upgrade.activate(true).await?;
+            dry_run("重启caddy服务");
         } else {
-            return Err(anyhow::anyhow!(
-                "caddy version执行失败！退出码：{}",
-                if let Some(code) = status.code() {
-                    code.to_string()
-                } else {
-                    "None".into()
-                }
-            ));
+            switch(&upgrade.cache, &upgrade.version, &caddy).await?;
+            upgrade.cache.prune(upgrade.retain).await?;
         }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+pub async fn rollback(config: &Config) -> Result<(), anyhow::Error> {
+    let tool = &get_config(config)?.tool;
+    let cache = tool.cache("caddy")?;
+    let version = cache.previous().await?;
+
+    if config.dry_run {
+        dry_run(format!("切换caddy至：{}", version));
+        dry_run("重启caddy服务");
     } else {
-        return Err(anyhow::anyhow!("需要先手动部署一个初始版本的caddy！"));
+        switch(&cache, &version, &cache.link()).await?;
     }
 
-    if need_fetch {
-        #[cfg(windows)]
+    version::pin_rollback(config, "caddy", &tool.version, &version).await?;
+    tracing::warn!("已回滚caddy至{}，修改配置中的版本后才会再次升级", version);
+    Ok(())
+}
+
+// 切换当前版本并重启服务
+#[cfg(not(target_os = "macos"))]
+async fn switch(cache: &ToolCache, version: &str, caddy: &Path) -> Result<(), anyhow::Error> {
+    #[cfg(windows)]
+    {
         use windows_service::{
             service::ServiceAccess,
             service_manager::{ServiceManager, ServiceManagerAccess},
         };
 
-        #[cfg(windows)]
         tracing::info!("正在连接本地服务……");
-
-        #[cfg(windows)]
         let service = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?
             .open_service("caddy", ServiceAccess::START | ServiceAccess::STOP)?;
 
-        #[cfg(target_os = "linux")]
-        const SUFFIX: &str = "linux_amd64.tar.gz";
-        #[cfg(target_os = "windows")]
-        const SUFFIX: &str = "windows_amd64.zip";
-
-        let url = format!(
-            "https://github.com/caddyserver/caddy/releases/download/v{}/caddy_{}_{}",
-            version, version, SUFFIX
-        );
-        tracing::info!("正在GET：{}", url);
-
-        let bytes = reqwest::get(url).await?.error_for_status()?.bytes().await?;
-
-        if bytes.is_empty() {
-            return Err(anyhow::anyhow!("未下载任何内容！"));
-        } else {
-            tracing::info!(
-                "已下载：{} MB",
-                retain_decimal_places(bytes.len() as f64 / 1024.0 / 1024.0, 3)
-            );
-            tracing::info!("正在解压……");
-
-            let (name, contents) = unzip(&bytes, "caddy")?;
-
-            #[cfg(windows)]
-            {
-                tracing::info!("正在停止服务……");
-                service.stop()?;
-            }
-
-            tracing::info!(
-                "正在保存：{:?}（{} MB）",
-                name,
-                retain_decimal_places(contents.len() as f64 / 1024.0 / 1024.0, 3)
-            );
+        tracing::info!("正在停止服务……");
+        service.stop()?;
+        tracing::info!("正在替换：{}", caddy.display());
+        cache.activate(version).await?;
 
-            let path = exe.with_file_name(name);
-            fs::write(&path, contents).await?;
-
-            #[cfg(not(windows))]
-            crate::utils::chmod_exec(path).await?;
+        tracing::info!("正在启动服务……");
+        service.start::<&str>(&[])?;
+    }
 
-            #[cfg(not(windows))]
-            {
-                tracing::info!("正在停止服务……");
-                spawn_command(Command::new(&caddy).arg("stop"), "caddy stop").await?;
-            }
+    #[cfg(not(windows))]
+    {
+        cache.activate(version).await?;
 
-            tracing::info!("正在启动服务……");
+        tracing::info!("正在停止服务……");
+        spawn_command(Command::new(caddy).arg("stop"), "caddy stop").await?;
 
-            #[cfg(windows)]
-            service.start::<&str>(&[])?;
-            #[cfg(not(windows))]
-            spawn_command(Command::new(&caddy).arg("start"), "caddy start").await?;
-        }
+        tracing::info!("正在启动服务……");
+        spawn_command(Command::new(caddy).arg("start"), "caddy start").await?;
     }
 
     Ok(())
@@ -263,6 +250,11 @@ pub async fn upgrade(_config: &Config) -> Result<(), anyhow::Error> {
     Err(anyhow::anyhow!("不支持macOS！"))
 }
 
+#[cfg(target_os = "macos")]
+pub async fn rollback(_config: &Config) -> Result<(), anyhow::Error> {
+    Err(anyhow::anyhow!("不支持macOS！"))
+}
+
 fn get_config(config: &Config) -> Result<&CaddyConfig, anyhow::Error> {
     config
         .caddy
@@ -271,32 +263,41 @@ fn get_config(config: &Config) -> Result<&CaddyConfig, anyhow::Error> {
 }
 
 pub async fn deploy(config: &Config) -> Result<(), anyhow::Error> {
+    let is_dry_run = config.dry_run;
+    let storage = StorageConfig::resolve(&config.storage)?;
+    let gitops = config;
     let config = get_config(config)?;
-    let mut oss = config
+    let deploy = config
         .deploy
         .clone()
-        .ok_or(anyhow::anyhow!("找不到[caddy.deploy]字段！"))?
-        .oss;
-
-    oss.access_key_id.replace(env_var("OSS_ACCESS_KEY_ID")?);
-    oss.access_key_secret
-        .replace(env_var("OSS_ACCESS_KEY_SECRET")?);
-    oss.ops_bucket.replace(env_var("OSS_OPS_BUCKET")?);
-    oss.ops_endpoint.replace(env_var("OSS_OPS_ENDPOINT")?);
+        .ok_or(anyhow::anyhow!("找不到[caddy.deploy]字段！"))?;
+    let oss = deploy.oss;
+    let mut commit = CommitInfo::new(Path::new("."), "ops").await;
 
     tracing::info!("正在生成Caddyfile……");
     let caddyfile = config.get_caddyfile()?;
 
+    if is_dry_run {
+        return plan_deploy(&caddyfile, &commit.render(&deploy.message)).await;
+    }
+
+    let bucket = storage.bucket_from_env("OSS_OPS")?;
+    // 上传到服务器的配置中写入解析后的精确版本，保证各台服务器一致
+    let version = version::resolve(gitops, "caddy", Some(CADDY_REPO), &config.tool.version).await?;
+
     tracing::info!("正在保存Caddyfile……");
     fs::write("Caddyfile", &caddyfile).await?;
 
     tracing::info!("正在提交git……");
     spawn_command(Command::new("git").arg("add").arg("Caddyfile"), "git").await?;
+    commit.count_staged(Path::new(".")).await?;
+    let message = commit.render(&deploy.message);
 
+    tracing::info!("提交信息：{}", message);
     if Command::new("git")
         .arg("commit")
         .arg("-m")
-        .arg("版本控制生成的Caddyfile")
+        .arg(&message)
         .spawn()?
         .wait()
         .await?
@@ -308,33 +309,69 @@ pub async fn deploy(config: &Config) -> Result<(), anyhow::Error> {
         tracing::warn!("没有可以提交的内容！");
     }
 
-    tracing::info!("正在初始化OSS Operator……");
-    let op = Operator::new(
-        Oss::default()
-            .root(&oss.root)
-            .access_key_id(oss.access_key_id.as_ref().unwrap())
-            .access_key_secret(oss.access_key_secret.as_ref().unwrap())
-            .bucket(oss.ops_bucket.as_ref().unwrap())
-            .endpoint(oss.ops_endpoint.as_ref().unwrap()),
-    )?
-    .layer(MimeGuessLayer::default())
-    .finish();
+    let op = storage.operator(&oss.root, bucket.as_ref())?;
+
+    let mut manifest = Manifest::new(&Releases::new_id(), Path::new(".")).await;
 
     tracing::info!("正在上传：Caddyfile");
+    manifest.push("Caddyfile", &caddyfile);
     op.write("Caddyfile", caddyfile).await?;
 
     tracing::info!("正在上传处理后的：gitops.toml");
-    op.write(
-        "gitops.toml",
-        toml::to_string_pretty(&Config::from(CaddyConfig::version(&config.version)))?,
-    )
-    .await?;
+    let gitops_toml = toml::to_string_pretty(&Config::from(config.pinned(&version)))?;
+    manifest.push("gitops.toml", &gitops_toml);
+    op.write("gitops.toml", gitops_toml).await?;
 
     let sync_toml = Path::new("sync.toml");
     if sync_toml.is_file() {
         tracing::info!("正在上传：sync.toml");
-        op.write("sync.toml", fs::read(sync_toml).await?).await?;
+        let contents = fs::read(sync_toml).await?;
+        manifest.push("sync.toml", &contents);
+        op.write("sync.toml", contents).await?;
+    }
+
+    manifest.write(&op).await
+}
+
+pub async fn show_manifest(config: &Config) -> Result<(), anyhow::Error> {
+    let storage = StorageConfig::resolve(&config.storage)?;
+    let oss = get_config(config)?
+        .deploy
+        .as_ref()
+        .ok_or(anyhow::anyhow!("找不到[caddy.deploy]字段！"))?
+        .oss
+        .clone();
+    let op = storage.operator(&oss.root, storage.bucket_from_env("OSS_OPS")?.as_ref())?;
+
+    Manifest::read(&op).await?.print();
+    Ok(())
+}
+
+async fn plan_deploy(caddyfile: &str, message: &str) -> Result<(), anyhow::Error> {
+    let old = fs::read_to_string("Caddyfile").await.unwrap_or_default();
+
+    if old == caddyfile {
+        dry_run("Caddyfile没有变化");
+    } else {
+        dry_run(format!(
+            "Caddyfile变更：\n{}",
+            TextDiff::from_lines(old.as_str(), caddyfile)
+                .unified_diff()
+                .header("Caddyfile", "Caddyfile")
+        ));
+        dry_run("执行：git add Caddyfile");
+        dry_run(format!("执行：git commit -m {}", message));
+        dry_run("执行：git push");
     }
 
+    dry_run("上传：Caddyfile");
+    dry_run("上传：gitops.toml");
+
+    if Path::new("sync.toml").is_file() {
+        dry_run("上传：sync.toml");
+    }
+
+    dry_run(format!("上传：{}", MANIFEST));
+
     Ok(())
 }
@@ -1,49 +1,258 @@
 use super::super::{
-    opendal_fs::{sync_dir, ConcurrentUploadTasks},
-    utils::{env_var, retain_decimal_places, spawn_command, unzip},
+    askpass::Askpass,
+    asset::Tool,
+    commit::CommitInfo,
+    compress::CompressionConfig,
+    manifest::{Manifest, MANIFEST},
+    mirror::{mirror, KeepList},
+    opendal_fs::{collect_files, ConcurrentUploadTasks, ObjectRule, ObjectRules},
+    release::Releases,
+    storage::{Bucket, StorageConfig},
+    tool::{self, ToolConfig},
+    utils::{command_line, dry_run, env_var, spawn_command, unique_temp_dir},
     Config,
 };
-use fs_extra::dir;
-use opendal::{layers::MimeGuessLayer, services::Oss, Operator};
 use serde::{Deserialize, Serialize};
-use std::{
-    env::{current_exe, set_current_dir},
-    ffi::OsStr,
-    path::{Path, PathBuf},
-};
+use std::path::{Path, PathBuf};
 use tokio::{
-    fs::{self, remove_dir_all},
+    fs::{create_dir_all, remove_dir_all},
     process::Command,
-    task::spawn_blocking,
 };
 
 #[derive(Deserialize, Serialize)]
 pub struct HugoConfig {
-    version: String,
+    #[serde(flatten)]
+    tool: ToolConfig,
+    #[serde(default)]
+    build: BuildConfig,
     deploy: Option<DeployConfig>,
 }
 
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+struct BuildConfig {
+    minify: Option<bool>,
+    environment: Option<String>,
+    gc: Option<bool>,
+    cache_dir: Option<String>,
+    source: Option<String>,
+    destination: Option<String>,
+    panic_on_warning: Option<bool>,
+    extra_args: Vec<String>,
+}
+
+impl BuildConfig {
+    // 环境中的配置覆盖全局配置，extra_args则追加在后面
+    fn merge(&self, over: &Self) -> Self {
+        Self {
+            minify: over.minify.or(self.minify),
+            environment: over.environment.clone().or(self.environment.clone()),
+            gc: over.gc.or(self.gc),
+            cache_dir: over.cache_dir.clone().or(self.cache_dir.clone()),
+            source: over.source.clone().or(self.source.clone()),
+            destination: over.destination.clone().or(self.destination.clone()),
+            panic_on_warning: over.panic_on_warning.or(self.panic_on_warning),
+            extra_args: self
+                .extra_args
+                .iter()
+                .chain(&over.extra_args)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn apply(&self, hugo: &mut Command) {
+        let flags = [
+            ("--minify", self.minify),
+            ("--gc", self.gc),
+            ("--panicOnWarning", self.panic_on_warning),
+        ];
+        let values = [
+            ("--environment", &self.environment),
+            ("--cacheDir", &self.cache_dir),
+            ("--source", &self.source),
+            ("--destination", &self.destination),
+        ];
+
+        for (flag, _) in flags.iter().filter(|(_, on)| *on == Some(true)) {
+            hugo.arg(flag);
+        }
+
+        for (flag, value) in values {
+            if let Some(value) = value {
+                hugo.arg(flag).arg(value);
+            }
+        }
+
+        hugo.args(&self.extra_args);
+    }
+
+    // hugo的--destination是相对于--source的
+    fn public_dir(&self) -> PathBuf {
+        Path::new(self.source.as_deref().unwrap_or(""))
+            .join(self.destination.as_deref().unwrap_or("public"))
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 struct DeployConfig {
-    github: GithubConfig,
+    #[serde(alias = "github")]
+    git: GitConfig,
     oss: OssConfig,
+    #[serde(default = "EnvironmentConfig::defaults")]
+    environments: Vec<EnvironmentConfig>,
+}
+
+impl DeployConfig {
+    fn environment(&self, name: &str) -> Result<&EnvironmentConfig, anyhow::Error> {
+        self.environments
+            .iter()
+            .find(|e| e.name == name)
+            .ok_or(anyhow::anyhow!("找不到部署环境：{}", name))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct EnvironmentConfig {
+    name: String,
+    base_url: Option<String>,
+    base_url_env: Option<String>,
+    #[serde(default)]
+    build: BuildConfig,
+    branch: Option<String>,
+    bucket: Option<String>,
+    endpoint: Option<String>,
+}
+
+impl EnvironmentConfig {
+    // 未配置环境时沿用原先的draft + prod两步部署
+    fn defaults() -> Vec<Self> {
+        vec![
+            Self {
+                name: "draft".into(),
+                base_url: None,
+                base_url_env: Some("HUGO_DRAFT_BASE_URL".into()),
+                build: BuildConfig {
+                    extra_args: vec!["-D".into(), "-F".into()],
+                    ..Default::default()
+                },
+                branch: Some("draft".into()),
+                bucket: None,
+                endpoint: None,
+            },
+            Self {
+                name: "prod".into(),
+                base_url: None,
+                base_url_env: None,
+                build: Default::default(),
+                branch: None,
+                bucket: None,
+                endpoint: None,
+            },
+        ]
+    }
+
+    // 第二项表示是否来自环境变量，需要在日志中打码
+    fn base_url(&self) -> Result<Option<(String, bool)>, anyhow::Error> {
+        match (&self.base_url, &self.base_url_env) {
+            (Some(base_url), _) => Ok(Some((base_url.clone(), false))),
+            (None, Some(key)) => Ok(Some((env_var(key)?, true))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn bucket(&self, storage: &StorageConfig) -> Result<Option<Bucket>, anyhow::Error> {
+        match &self.bucket {
+            Some(name) => Ok(Some(Bucket {
+                name: name.clone(),
+                endpoint: self.endpoint.clone(),
+            })),
+            None => storage.bucket_from_env(&format!(
+                "OSS_{}",
+                self.name.to_uppercase().replace('-', "_")
+            )),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
-struct GithubConfig {
-    username: String,
-    org: String,
-    repo: String,
+struct GitConfig {
+    // 任意git远端：GitHub、GitLab、Gitee、Gitea或本地裸仓库路径；不填时由org与repo拼出GitHub地址
+    url: Option<String>,
+    username: Option<String>,
+    org: Option<String>,
+    repo: Option<String>,
     access_token: Option<String>,
     user_email: Option<String>,
     user_name: Option<String>,
+    // 仓库中存放站点的目录，"."表示仓库根目录
+    #[serde(default = "GitConfig::default_target")]
+    target: String,
+    #[serde(default = "GitConfig::default_mirror")]
+    mirror: bool,
+    #[serde(default)]
+    keep: Vec<String>,
+    // 提交信息模板，占位符见commit模块
+    #[serde(default = "GitConfig::default_message")]
+    message: String,
+}
+
+impl GitConfig {
+    fn default_target() -> String {
+        "public".into()
+    }
+
+    fn default_mirror() -> bool {
+        true
+    }
+
+    fn default_message() -> String {
+        "Deploy".into()
+    }
+
+    fn remote(&self) -> Result<String, anyhow::Error> {
+        match (&self.url, &self.org, &self.repo) {
+            (Some(url), _, _) => Ok(url.clone()),
+            (None, Some(org), Some(repo)) => Ok(format!("https://github.com/{}/{}.git", org, repo)),
+            _ => Err(anyhow::anyhow!(
+                "[hugo.deploy.git]需要配置url，或者同时配置org与repo！"
+            )),
+        }
+    }
+
+    fn askpass(&self) -> Result<Option<Askpass>, anyhow::Error> {
+        match &self.access_token {
+            Some(token) => Ok(Some(Askpass::new(
+                self.username.as_deref().unwrap_or("git"),
+                token,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    fn dir(&self) -> Result<String, anyhow::Error> {
+        if let Some(repo) = &self.repo {
+            return Ok(repo.clone());
+        }
+
+        let remote = self.remote()?;
+        let name = remote
+            .trim_end_matches('/')
+            .rsplit(['/', ':', '\\'])
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches(".git");
+
+        match name {
+            "" => Err(anyhow::anyhow!("无法从{}推断仓库目录名！", remote)),
+            name => Ok(name.into()),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 struct OssConfig {
     sync: OssSyncConfig,
-    access_key_id: Option<String>,
-    access_key_secret: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -51,195 +260,229 @@ struct OssSyncConfig {
     root: String,
     files: Vec<String>,
     dirs: Vec<String>,
+    // 开启后上传到<root>/releases/<id>/并把<root>/current写为该id，<root>下的线上文件不会更新，
+    // 需要由服务端（例如caddy的rewrite）读取current，把请求指向对应的发布版本目录
+    releases: Option<ReleasesConfig>,
+    #[serde(default)]
+    rules: Vec<ObjectRule>,
+    compression: Option<CompressionConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct ReleasesConfig {
+    #[serde(default = "ReleasesConfig::default_retain")]
+    retain: usize,
+}
+
+impl ReleasesConfig {
+    fn default_retain() -> usize {
+        5
+    }
 }
 
+const HUGO_REPO: &str = "gohugoio/hugo";
+
 pub struct Hugo(PathBuf);
 
+// 部署各步骤用到的路径都显式传入，不依赖也不修改进程的工作目录
+struct Workspace {
+    // hugo站点根目录
+    source: PathBuf,
+    // hugo的输出目录
+    public: PathBuf,
+    // 部署仓库的克隆目录，位于源码树之外的临时目录中，避免覆盖源码树中的同名目录
+    repo: PathBuf,
+    work: PathBuf,
+}
+
+impl Workspace {
+    fn new(source: &Path, build: &BuildConfig, git: &GitConfig) -> Result<Self, anyhow::Error> {
+        let work = unique_temp_dir("gitops-deploy")?;
+
+        Ok(Self {
+            source: source.to_owned(),
+            public: source.join(build.public_dir()),
+            repo: work.join(git.dir()?),
+            work,
+        })
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.work).ok();
+    }
+}
+
 impl Hugo {
     pub async fn upgrade(config: &Config) -> Result<(Self, &HugoConfig), anyhow::Error> {
-        let config = config
+        let hugo_config = config
             .hugo
             .as_ref()
             .ok_or(anyhow::anyhow!("找不到[hugo]字段！"))?;
-        let version = &config.version;
-
-        tracing::info!("请求的hugo版本是：{}", version);
-        tracing::info!("正在校验现有hugo版本……");
-
-        let exe = current_exe()?;
-        let hugo = exe.with_file_name("hugo");
-        let mut need_fetch = true;
-
-        if let Ok(output) = Command::new(&hugo).arg("version").output().await {
-            let status = output.status;
-
-            if status.success() {
-                if output
-                    .stdout
-                    .starts_with(format!("hugo v{}", version).as_bytes())
-                {
-                    need_fetch = false;
-                    tracing::info!("现有hugo版本匹配！将跳过下载");
-                } else {
-                    tracing::info!("现有hugo版本不匹配，准备更新hugo");
-                }
-            } else {
-                return Err(anyhow::anyhow!(
-                    "hugo version执行失败！退出码：{}",
-                    if let Some(code) = status.code() {
-                        code.to_string()
-                    } else {
-                        "None".into()
-                    }
-                ));
-            }
-        } else {
-            tracing::info!("hugo不存在，准备下载hugo");
-        }
+        let tool = hugo_config.tool.or(ToolConfig::github(
+            HUGO_REPO,
+            "hugo_{version}_checksums.txt",
+        ));
 
-        if need_fetch {
-            #[cfg(target_os = "macos")]
-            const SUFFIX: &str = "darwin-universal.tar.gz";
-            #[cfg(target_os = "linux")]
-            const SUFFIX: &str = "Linux-64bit.tar.gz";
-            #[cfg(target_os = "windows")]
-            const SUFFIX: &str = "windows-amd64.zip";
-
-            let url = format!(
-                "https://github.com/gohugoio/hugo/releases/download/v{}/hugo_extended_{}_{}",
-                version, version, SUFFIX
-            );
-            tracing::info!("正在GET：{}", url);
-
-            let bytes = reqwest::get(url).await?.error_for_status()?.bytes().await?;
-
-            if bytes.is_empty() {
-                return Err(anyhow::anyhow!("未下载任何内容！"));
-            } else {
-                tracing::info!(
-                    "已下载：{} MB",
-                    retain_decimal_places(bytes.len() as f64 / 1024.0 / 1024.0, 3)
-                );
-                tracing::info!("正在解压……");
-
-                let (name, contents) = unzip(&bytes, "hugo")?;
-                tracing::info!(
-                    "正在保存：{:?}（{} MB）",
-                    name,
-                    retain_decimal_places(contents.len() as f64 / 1024.0 / 1024.0, 3)
-                );
-
-                let path = exe.with_file_name(name);
-                fs::write(&path, contents).await?;
-
-                #[cfg(not(windows))]
-                crate::utils::chmod_exec(path).await?;
-            }
+        if let Some(upgrade) = tool::prepare(config, "hugo", &tool, Some(Tool::Hugo)).await? {
+            upgrade.activate(config.dry_run).await?;
         }
 
-        Ok((Self(hugo), config))
+        Ok((Self(tool.cache("hugo")?.link()), hugo_config))
+    }
+
+    pub async fn rollback(config: &Config) -> Result<(), anyhow::Error> {
+        let hugo_config = config
+            .hugo
+            .as_ref()
+            .ok_or(anyhow::anyhow!("找不到[hugo]字段！"))?;
+        tool::rollback_one(config, "hugo", &hugo_config.tool).await
     }
 
     async fn deploy_step(
         &self,
+        source: &Path,
         config: &DeployConfig,
-        for_draft: bool,
+        build: &BuildConfig,
+        env: &EnvironmentConfig,
+        storage: &StorageConfig,
+        is_dry_run: bool,
     ) -> Result<(), anyhow::Error> {
-        tracing::info!(
-            "正在hugo deploy {}版本……",
-            if for_draft { "draft" } else { "production" }
-        );
+        tracing::info!("正在hugo deploy {}环境……", env.name);
 
-        remove_public().await?;
+        let build = build.merge(&env.build);
+        let ws = Workspace::new(source, &build, &config.git)?;
 
+        if !is_dry_run {
+            remove_dir(&ws.public).await?;
+        }
+
+        let base_url = env.base_url()?;
         let mut hugo = Command::new(&self.0);
-        let (hugo, base_url) = if for_draft {
-            let base_url = env_var("HUGO_DRAFT_BASE_URL")?;
-            (
-                hugo.arg("-b").arg(&base_url).arg("-D").arg("-F"),
-                Some(base_url),
-            )
-        } else {
-            (&mut hugo, None)
+        hugo.current_dir(&ws.source);
+
+        if let Some((base_url, _)) = &base_url {
+            hugo.arg("-b").arg(base_url);
+        }
+
+        build.apply(&mut hugo);
+
+        let secrets = match &base_url {
+            Some((base_url, true)) => vec![base_url.as_str()],
+            _ => Vec::new(),
         };
+        let command_line = command_line(&hugo, &secrets);
 
-        if let Some(base_url) = base_url {
-            tracing::info!(
-                "正在执行：hugo {}",
-                hugo.as_std()
-                    .get_args()
-                    .collect::<Vec<&OsStr>>()
-                    .join(" ".as_ref())
-                    .to_string_lossy()
-                    .replace(&base_url, "****")
-            );
+        if is_dry_run {
+            dry_run(format!("执行：{}", command_line));
         } else {
-            tracing::info!("正在执行：hugo");
+            tracing::info!("正在执行：{}", command_line);
+            spawn_command(&mut hugo, "hugo").await?;
         }
-        spawn_command(hugo, "hugo").await?;
 
-        deploy_github(&config.github, for_draft).await?;
-        deploy_oss(&config.oss, for_draft).await
+        deploy_git(&config.git, env, &ws, is_dry_run).await?;
+        deploy_oss(&config.oss, env, storage, &ws, is_dry_run).await
     }
 }
 
-pub async fn deploy(config: &Config) -> Result<(), anyhow::Error> {
-    let (hugo, config) = Hugo::upgrade(&config).await?;
+pub async fn deploy(config: &Config, source: &Path, envs: &[String]) -> Result<(), anyhow::Error> {
+    let is_dry_run = config.dry_run;
+    let storage = StorageConfig::resolve(&config.storage)?;
+    let (hugo, config) = Hugo::upgrade(config).await?;
+    let build = config.build.clone();
     let mut config = config
         .deploy
         .clone()
         .ok_or(anyhow::anyhow!("找不到[hugo.deploy]字段！"))?;
 
+    // 本地或ssh远端不需要token
+    config.git.access_token = env_var("DEPLOY_GITHUB_ACCESS_TOKEN").ok();
     config
-        .github
-        .access_token
-        .replace(env_var("DEPLOY_GITHUB_ACCESS_TOKEN")?);
-    config
-        .github
+        .git
         .user_email
         .replace(env_var("DEPLOY_GITHUB_USER_EMAIL")?);
     config
-        .github
+        .git
         .user_name
         .replace(env_var("DEPLOY_GITHUB_USER_NAME")?);
-    config
-        .oss
-        .access_key_id
-        .replace(env_var("OSS_ACCESS_KEY_ID")?);
-    config
-        .oss
-        .access_key_secret
-        .replace(env_var("OSS_ACCESS_KEY_SECRET")?);
 
-    tracing::info!("================");
-    hugo.deploy_step(&config, true).await?;
+    for name in envs {
+        config.environment(name)?;
+    }
 
-    tracing::info!("================");
-    hugo.deploy_step(&config, false).await?;
+    for env in config
+        .environments
+        .iter()
+        .filter(|e| envs.is_empty() || envs.contains(&e.name))
+    {
+        tracing::info!("================");
+        hugo.deploy_step(source, &config, &build, env, &storage, is_dry_run)
+            .await?;
+    }
 
     Ok(())
 }
 
-async fn deploy_github(config: &GithubConfig, for_draft: bool) -> Result<(), anyhow::Error> {
+async fn deploy_git(
+    config: &GitConfig,
+    env: &EnvironmentConfig,
+    ws: &Workspace,
+    is_dry_run: bool,
+) -> Result<(), anyhow::Error> {
     tracing::info!(
-        "正在deploy github {}",
-        if for_draft { "draft" } else { "main" }
+        "正在deploy git {}",
+        env.branch.as_deref().unwrap_or("默认分支")
     );
 
-    let repo = &config.repo;
-    let access_token = config.access_token.as_ref().unwrap();
-    let url = format!(
-        "https://{}:{}@github.com/{}/{}.git",
-        config.username, access_token, config.org, repo
-    );
+    let repo = &ws.repo;
+    let target = repo.join(&config.target);
+    let url = config.remote()?;
+    let mut commit = CommitInfo::new(&ws.source, &env.name).await;
+
+    if is_dry_run {
+        dry_run(format!("执行：git clone {} {}", url, repo.display()));
+        if let Some(branch) = &env.branch {
+            dry_run(format!("执行：git checkout {}", branch));
+        }
+        dry_run(format!(
+            "{}{}目录至{}",
+            if config.mirror { "镜像" } else { "拷贝" },
+            ws.public.display(),
+            target.display()
+        ));
+        dry_run("执行：git add .");
+        dry_run(format!(
+            "执行：git commit -m {}",
+            commit.render(&config.message)
+        ));
+        dry_run("执行：git push");
+        return Ok(());
+    }
 
-    tracing::info!("正在执行：git clone {}", url.replace(access_token, "****"));
-    spawn_command(Command::new("git").arg("clone").arg(url), "git").await?;
-    set_current_dir(repo)?;
+    let askpass = config.askpass()?;
+    // 所有命令都通过-C在仓库目录中执行，只有需要访问远端的命令才带上凭据
+    let git = |remote: bool| {
+        let mut git = Command::new("git");
+        git.arg("-C").arg(repo);
+        if let (true, Some(askpass)) = (remote, &askpass) {
+            askpass.apply(&mut git);
+        }
+        git
+    };
+
+    create_dir_all(&ws.work).await?;
+
+    tracing::info!("正在执行：git clone {} {}", url, repo.display());
+    let mut clone = Command::new("git");
+    if let Some(askpass) = &askpass {
+        askpass.apply(&mut clone);
+    }
+    spawn_command(clone.arg("clone").arg(&url).arg(repo), "git").await?;
 
     tracing::info!("正在配置git环境……");
     spawn_command(
-        Command::new("git")
+        git(false)
             .arg("config")
             .arg("user.email")
             .arg(config.user_email.as_ref().unwrap()),
@@ -247,7 +490,7 @@ async fn deploy_github(config: &GithubConfig, for_draft: bool) -> Result<(), any
     )
     .await?;
     spawn_command(
-        Command::new("git")
+        git(false)
             .arg("config")
             .arg("user.name")
             .arg(config.user_name.as_ref().unwrap()),
@@ -255,83 +498,357 @@ async fn deploy_github(config: &GithubConfig, for_draft: bool) -> Result<(), any
     )
     .await?;
 
-    if for_draft {
-        tracing::info!("正在执行：git checkout draft");
-        spawn_command(Command::new("git").arg("checkout").arg("draft"), "git").await?;
+    if let Some(branch) = &env.branch {
+        tracing::info!("正在执行：git checkout {}", branch);
+        spawn_command(git(false).arg("checkout").arg(branch), "git").await?;
     }
 
-    remove_public().await?;
-
-    tracing::info!("正在拷贝public目录……");
-    spawn_blocking(move || dir::copy("../public", "", &Default::default())).await??;
+    tracing::info!(
+        "正在{}{}目录至{}……",
+        if config.mirror { "镜像" } else { "拷贝" },
+        ws.public.display(),
+        target.display()
+    );
+    let stats = mirror(
+        &ws.public,
+        &target,
+        config.mirror,
+        &KeepList::new(&config.keep)?,
+    )
+    .await?;
+    tracing::info!(
+        "已拷贝：{}，已删除：{}，已保留：{}",
+        stats.copied,
+        stats.deleted,
+        stats.kept
+    );
 
     tracing::info!("正在提交……");
-    spawn_command(Command::new("git").arg("add").arg("."), "git").await?;
+    spawn_command(git(false).arg("add").arg("."), "git").await?;
+    commit.count_staged(repo).await?;
+    let message = commit.render(&config.message);
 
-    if Command::new("git")
+    tracing::info!("提交信息：{}", message);
+    if git(false)
         .arg("commit")
         .arg("-m")
-        .arg("Deploy")
+        .arg(&message)
         .spawn()?
         .wait()
         .await?
         .success()
     {
         tracing::info!("正在执行：git push");
-        spawn_command(Command::new("git").arg("push"), "git").await?;
+        spawn_command(git(true).arg("push"), "git").await?;
     } else {
         tracing::warn!("没有可以提交的内容！");
     }
 
-    tracing::info!("正在清理{}目录……", repo);
-    set_current_dir("..")?;
-    Ok(remove_dir_all(repo).await?)
+    Ok(())
 }
 
-async fn deploy_oss(config: &OssConfig, for_draft: bool) -> Result<(), anyhow::Error> {
-    tracing::info!(
-        "正在deploy oss {}",
-        if for_draft { "draft" } else { "prod" }
-    );
-    set_current_dir("public")?;
-
-    tracing::info!("正在初始化Operator……");
+async fn deploy_oss(
+    config: &OssConfig,
+    env: &EnvironmentConfig,
+    storage: &StorageConfig,
+    ws: &Workspace,
+    is_dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    tracing::info!("正在deploy oss {}", env.name);
+    let public = &ws.public;
     let sync = &config.sync;
-    let oss = Oss::default()
-        .root(&sync.root)
-        .access_key_id(config.access_key_id.as_ref().unwrap())
-        .access_key_secret(config.access_key_secret.as_ref().unwrap());
-    let oss = if for_draft {
-        oss.bucket(&env_var("OSS_DRAFT_BUCKET")?)
-            .endpoint(&env_var("OSS_DRAFT_ENDPOINT")?)
-    } else {
-        oss.bucket(&env_var("OSS_PROD_BUCKET")?)
-            .endpoint(&env_var("OSS_PROD_ENDPOINT")?)
+    let rules = ObjectRules::new(&sync.rules)?;
+    let bucket = env.bucket(storage)?;
+    let deploy_id = Releases::new_id();
+    let (op, release) = match &sync.releases {
+        Some(releases_config) => {
+            let releases = Releases::new(storage, &sync.root, bucket)?;
+            tracing::info!("本次发布版本：{}", deploy_id);
+
+            if releases.exists(&deploy_id).await? {
+                return Err(anyhow::anyhow!("发布版本{}已存在！", deploy_id));
+            }
+
+            (
+                releases.operator(&deploy_id)?,
+                Some((releases, releases_config)),
+            )
+        }
+        None => (storage.operator(&sync.root, bucket.as_ref())?, None),
+    };
+    let tasks = || {
+        ConcurrentUploadTasks::new(op.clone(), storage)
+            .with_rules(&rules)
+            .with_compression(sync.compression.as_ref())
     };
 
-    let op = Operator::new(oss)?
-        .layer(MimeGuessLayer::default())
-        .finish();
+    if is_dry_run {
+        return plan_oss(
+            tasks(),
+            sync,
+            public,
+            release.map(|(_, c)| (deploy_id, c.retain)),
+        )
+        .await;
+    }
 
     tracing::info!("开始上传文件……");
-    let mut files = ConcurrentUploadTasks::new(op.clone());
-    files.push_str_seq(&sync.files).await?;
+    let mut files = tasks();
+    files.push_str_seq(public, &sync.files).await?;
     files.join().await?;
 
     tracing::info!("开始同步目录……");
     for dir in &sync.dirs {
         tracing::info!("正在同步目录：{}", dir);
-        sync_dir(&op, dir).await?;
+        tasks().sync_dir(public, dir).await?;
+    }
+
+    tracing::info!("正在生成部署清单……");
+    let mut manifest = Manifest::new(&deploy_id, &ws.source).await;
+
+    for f in &sync.files {
+        manifest.push_file(public, f).await?;
+    }
+
+    for dir in &sync.dirs {
+        for path in collect_files(public.join(dir)).await? {
+            manifest.push_file(public, path).await?;
+        }
+    }
+
+    manifest.write(&op).await?;
+
+    if let Some((releases, releases_config)) = release {
+        releases.point_to(&deploy_id).await?;
+        releases.prune(releases_config.retain).await?;
     }
 
-    Ok(set_current_dir("..")?)
+    Ok(())
 }
 
-async fn remove_public() -> Result<(), anyhow::Error> {
-    let public = Path::new("public");
+async fn plan_oss(
+    tasks: ConcurrentUploadTasks,
+    sync: &OssSyncConfig,
+    public: &Path,
+    release: Option<(String, usize)>,
+) -> Result<(), anyhow::Error> {
+    for f in &sync.files {
+        dry_run(format!("上传：{}", f));
+    }
+
     if public.is_dir() {
-        tracing::info!("正在清理public目录……");
-        remove_dir_all(public).await?;
+        for dir in &sync.dirs {
+            dry_run(format!("同步目录：{}", dir));
+            tasks.plan_dir(public, dir).await?.print();
+        }
+    } else {
+        tracing::warn!("{}目录不存在，无法计算目录同步计划！", public.display());
+    }
+
+    dry_run(format!("上传：{}", MANIFEST));
+
+    if let Some((id, retain)) = release {
+        dry_run(format!(
+            "切换当前发布版本至：{}，保留最近{}个版本",
+            id, retain
+        ));
     }
+
     Ok(())
 }
+
+pub async fn rollback(config: &Config, env: &str, to: Option<&str>) -> Result<(), anyhow::Error> {
+    let storage = StorageConfig::resolve(&config.storage)?;
+    let deploy = get_deploy_config(config)?;
+    let sync = &deploy.oss.sync;
+
+    if sync.releases.is_none() {
+        return Err(anyhow::anyhow!(
+            "未配置[hugo.deploy.oss.sync.releases]字段！"
+        ));
+    }
+
+    let releases = Releases::new(
+        &storage,
+        &sync.root,
+        deploy.environment(env)?.bucket(&storage)?,
+    )?;
+    let id = match to {
+        Some(id) => id.to_owned(),
+        None => releases.previous().await?,
+    };
+
+    if config.dry_run {
+        dry_run(format!("切换当前发布版本至：{}", id));
+        return Ok(());
+    }
+
+    releases.point_to(&id).await
+}
+
+pub async fn show_manifest(config: &Config, env: &str) -> Result<(), anyhow::Error> {
+    let storage = StorageConfig::resolve(&config.storage)?;
+    let deploy = get_deploy_config(config)?;
+    let sync = &deploy.oss.sync;
+    let bucket = deploy.environment(env)?.bucket(&storage)?;
+
+    let op = match sync.releases {
+        Some(_) => {
+            let releases = Releases::new(&storage, &sync.root, bucket)?;
+            let current = releases
+                .current()
+                .await?
+                .ok_or(anyhow::anyhow!("尚未发布过任何版本！"))?;
+            releases.operator(&current)?
+        }
+        None => storage.operator(&sync.root, bucket.as_ref())?,
+    };
+
+    Manifest::read(&op).await?.print();
+    Ok(())
+}
+
+fn get_deploy_config(config: &Config) -> Result<&DeployConfig, anyhow::Error> {
+    config
+        .hugo
+        .as_ref()
+        .ok_or(anyhow::anyhow!("找不到[hugo]字段！"))?
+        .deploy
+        .as_ref()
+        .ok_or(anyhow::anyhow!("找不到[hugo.deploy]字段！"))
+}
+
+async fn remove_dir(dir: &Path) -> Result<(), anyhow::Error> {
+    if dir.is_dir() {
+        tracing::info!("正在清理{}目录……", dir.display());
+        remove_dir_all(dir).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn git() -> GitConfig {
+        toml::from_str(r#"url = "https://example.org/org/pages.git""#).unwrap()
+    }
+
+    fn site() -> tempfile::TempDir {
+        let source = tempfile::tempdir().unwrap();
+        write(&source.path().join("public/index.html"), "index");
+        write(&source.path().join("public/posts/a.html"), "a");
+        write(&source.path().join("public/draft.html"), "draft");
+        source
+    }
+
+    fn oss(releases: bool) -> OssConfig {
+        let mut oss = String::from(
+            r#"
+            [sync]
+            root = "site"
+            files = ["index.html"]
+            dirs = ["posts"]
+            "#,
+        );
+
+        if releases {
+            oss.push_str("[sync.releases]\n");
+        }
+
+        toml::from_str(&oss).unwrap()
+    }
+
+    fn env() -> EnvironmentConfig {
+        toml::from_str(r#"name = "test""#).unwrap()
+    }
+
+    async fn manifest_paths(op: &opendal::Operator) -> Vec<String> {
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&op.read(MANIFEST).await.unwrap().to_vec()).unwrap();
+
+        manifest["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["path"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn workspace_clones_outside_the_source_tree() {
+        let source = tempfile::tempdir().unwrap();
+        write(&source.path().join("pages/content.md"), "source");
+
+        let ws = Workspace::new(source.path(), &BuildConfig::default(), &git()).unwrap();
+        let work = ws.work.clone();
+
+        assert_eq!(ws.public, source.path().join("public"));
+        assert!(ws.repo.ends_with("pages"));
+        assert!(!ws.repo.starts_with(source.path()));
+
+        fs::create_dir_all(&ws.repo).unwrap();
+        drop(ws);
+
+        assert!(!work.exists());
+        assert!(source.path().join("pages/content.md").is_file());
+    }
+
+    fn fs_storage(root: &Path) -> StorageConfig {
+        let config = format!("kind = \"fs\"\nroot = {:?}", root.to_str().unwrap());
+        StorageConfig::resolve(&Some(toml::from_str(&config).unwrap())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn deploy_oss_uploads_configured_files() {
+        let source = site();
+        let store = tempfile::tempdir().unwrap();
+        let storage = fs_storage(store.path());
+        let ws = Workspace::new(source.path(), &BuildConfig::default(), &git()).unwrap();
+
+        deploy_oss(&oss(false), &env(), &storage, &ws, false)
+            .await
+            .unwrap();
+
+        let op = storage.operator("site", None).unwrap();
+        assert_eq!(op.read("index.html").await.unwrap().to_vec(), b"index");
+        assert_eq!(op.read("posts/a.html").await.unwrap().to_vec(), b"a");
+        assert!(!op.is_exist("draft.html").await.unwrap());
+        assert_eq!(manifest_paths(&op).await, ["index.html", "posts/a.html"]);
+    }
+
+    #[tokio::test]
+    async fn deploy_oss_publishes_a_release_to_fs() {
+        let source = site();
+        let store = tempfile::tempdir().unwrap();
+        let storage = fs_storage(store.path());
+        let ws = Workspace::new(source.path(), &BuildConfig::default(), &git()).unwrap();
+
+        deploy_oss(&oss(true), &env(), &storage, &ws, false)
+            .await
+            .unwrap();
+
+        let releases = Releases::new(&storage, "site", None).unwrap();
+        let current = releases.current().await.unwrap().unwrap();
+        let op = releases.operator(&current).unwrap();
+
+        assert_eq!(op.read("index.html").await.unwrap().to_vec(), b"index");
+        assert_eq!(manifest_paths(&op).await, ["index.html", "posts/a.html"]);
+
+        // 同一秒内的第二次部署得到新的发布版本，不会覆盖上一次
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        deploy_oss(&oss(true), &env(), &storage, &ws, false)
+            .await
+            .unwrap();
+        let ids = releases.list().await.unwrap();
+
+        assert_eq!(ids.len(), 2);
+        assert_eq!(releases.current().await.unwrap().as_ref(), ids.last());
+    }
+}
@@ -1,47 +1,574 @@
-use super::super::{utils::env_var, Config};
-use opendal::{services::Oss, Operator};
+use super::super::{
+    opendal_fs::{
+        collect_files, compile_glob, list_remote, md5_hex, path_to_key, ConcurrentUploadTasks,
+        CONTENT_MD5,
+    },
+    storage::{Bucket, StorageConfig},
+    utils::{dry_run, spawn_command},
+    Config,
+};
+use globset::GlobMatcher;
+use opendal::{Metadata, Operator};
 use serde::{Deserialize, Serialize};
-use tokio::fs;
+use std::{collections::BTreeMap, io::ErrorKind, path::Path, time::Duration};
+use tokio::{fs, io::AsyncWriteExt, process::Command};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct SyncConfig {
     bucket: String,
     endpoint: String,
     root: String,
-    files: Vec<String>,
-    access_key_id: Option<String>,
-    access_key_secret: Option<String>,
+    files: Vec<SyncEntry>,
+    state_file: Option<String>,
+    on_change: Option<Vec<String>>,
 }
 
-pub async fn sync(config: &Config) -> Result<(), anyhow::Error> {
-    let mut config = config
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+enum SyncEntry {
+    Path(String),
+    Detailed(SyncFile),
+}
+
+impl SyncEntry {
+    fn file(&self) -> SyncFile {
+        match self {
+            Self::Path(path) => SyncFile {
+                path: path.clone(),
+                direction: Default::default(),
+                mode: None,
+                owner: None,
+            },
+            Self::Detailed(file) => file.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct SyncFile {
+    path: String,
+    #[serde(default)]
+    direction: Direction,
+    mode: Option<String>,
+    owner: Option<String>,
+}
+
+impl SyncFile {
+    fn mode(&self) -> Result<Option<u32>, anyhow::Error> {
+        self.mode
+            .as_ref()
+            .map(|m| {
+                u32::from_str_radix(m.trim_start_matches("0o"), 8)
+                    .map_err(|_| anyhow::anyhow!("非法的文件权限：{}", m))
+            })
+            .transpose()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum Direction {
+    #[default]
+    Pull,
+    Push,
+}
+
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Glob { base: String, matcher: GlobMatcher },
+}
+
+impl Pattern {
+    fn parse(path: &str) -> Result<Self, anyhow::Error> {
+        if path.contains(['*', '?', '[', '{']) {
+            let base = path
+                .split('/')
+                .take_while(|c| !c.contains(['*', '?', '[', '{']))
+                .collect::<Vec<&str>>()
+                .join("/");
+
+            Ok(Self::Glob {
+                base,
+                matcher: compile_glob(path)?,
+            })
+        } else if path.ends_with('/') {
+            Ok(Self::Prefix(path.trim_end_matches('/').into()))
+        } else {
+            Ok(Self::Exact(path.into()))
+        }
+    }
+
+    fn base(&self) -> &str {
+        match self {
+            Self::Exact(path) | Self::Prefix(path) => path,
+            Self::Glob { base, matcher: _ } => base,
+        }
+    }
+
+    fn filter(&self, keys: impl IntoIterator<Item = String>) -> Vec<String> {
+        let mut keys = keys
+            .into_iter()
+            .filter(|k| match self {
+                Self::Glob { base: _, matcher } => matcher.is_match(k),
+                _ => true,
+            })
+            .collect::<Vec<String>>();
+
+        keys.sort();
+        keys
+    }
+
+    async fn remote_keys(&self, op: &Operator) -> Result<Vec<String>, anyhow::Error> {
+        match self {
+            Self::Exact(path) => Ok(vec![path.clone()]),
+            _ => Ok(self.filter(list_remote(op, self.base()).await?.into_keys())),
+        }
+    }
+
+    async fn local_keys(&self) -> Result<Vec<String>, anyhow::Error> {
+        match self {
+            Self::Exact(path) => Ok(vec![path.clone()]),
+            _ => {
+                let base = match self.base() {
+                    "" => ".",
+                    base => base,
+                };
+
+                Ok(self.filter(
+                    collect_files(base)
+                        .await?
+                        .iter()
+                        .map(|p| Ok(path_to_key(p)?.trim_start_matches("./").to_owned()))
+                        .collect::<Result<Vec<String>, anyhow::Error>>()?,
+                ))
+            }
+        }
+    }
+}
+
+pub async fn sync(config: &Config, watch: bool, interval: Duration) -> Result<(), anyhow::Error> {
+    let is_dry_run = config.dry_run;
+    let storage = StorageConfig::resolve(&config.storage)?;
+    let config = config
         .sync
-        .clone()
+        .as_ref()
         .ok_or(anyhow::anyhow!("找不到[sync]字段！"))?;
 
-    config.access_key_id.replace(env_var("OSS_ACCESS_KEY_ID")?);
-    config
-        .access_key_secret
-        .replace(env_var("OSS_ACCESS_KEY_SECRET")?);
+    let op = storage.operator(
+        &config.root,
+        Some(&Bucket::new(&config.bucket, &config.endpoint)),
+    )?;
+    let state_file = config.state_file.as_deref().unwrap_or(STATE_FILE);
+    // 只有watch模式才缓存指纹，单次同步总是完整校验，以便修复被改动或损坏的本地文件
+    let mut state = if watch {
+        SyncState::load(state_file).await?
+    } else {
+        SyncState::default()
+    };
+
+    loop {
+        let mut result = sync_round(&op, &storage, config, &mut state, is_dry_run).await;
+
+        if watch && !is_dry_run {
+            result = result.and(state.save(state_file).await);
+        }
+
+        if !watch || is_dry_run {
+            return result;
+        }
+
+        if let Err(err) = result {
+            tracing::error!("本轮同步失败：{}", err);
+        }
+
+        tracing::info!("{}后再次检查……", humantime::format_duration(interval));
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => (),
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+async fn sync_round(
+    op: &Operator,
+    storage: &StorageConfig,
+    config: &SyncConfig,
+    state: &mut SyncState,
+    is_dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let mut round = Round::default();
+    let result = sync_once(op, storage, config, state, &mut round, is_dry_run).await;
+
+    // 整轮成功后才记录指纹；已经写入本地的文件即使本轮失败，也要在之后触发on_change
+    if result.is_ok() {
+        state.files.extend(round.fingerprints);
+    }
+    state.pending_hook |= round.changed > 0;
+    result?;
+
+    if state.pending_hook {
+        // on_change失败时保留标记，下一轮即使没有文件变化也会重试
+        run_on_change(config, is_dry_run).await?;
+        state.pending_hook = false;
+    }
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Round {
+    changed: usize,
+    fingerprints: BTreeMap<String, String>,
+}
+
+async fn sync_once(
+    op: &Operator,
+    storage: &StorageConfig,
+    config: &SyncConfig,
+    state: &SyncState,
+    round: &mut Round,
+    is_dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let mut push = ConcurrentUploadTasks::new(op.clone(), storage);
+    let trust_etag = storage.etag_is_md5();
+    let mut pushed = BTreeMap::new();
+
+    for entry in &config.files {
+        let file = entry.file();
+        let path = &file.path;
+        let mode = file.mode()?;
+        let pattern = Pattern::parse(path)?;
+
+        match file.direction {
+            Direction::Pull => {
+                let keys = pattern.remote_keys(op).await?;
+
+                if keys.is_empty() {
+                    tracing::warn!("远端没有匹配的文件：{}", path);
+                }
+
+                for f in keys {
+                    let meta = op.stat(&f).await?;
+                    let fingerprint = fingerprint(&meta);
+
+                    if state.is_fresh(&f, &fingerprint) && Path::new(&f).is_file() {
+                        tracing::debug!("没有变化：{}", f);
+                        continue;
+                    }
+
+                    if is_local_fresh(&f, &meta, trust_etag).await? {
+                        tracing::debug!("本地文件与远端一致：{}", f);
+                        round.fingerprints.insert(f, fingerprint);
+                        continue;
+                    }
+
+                    if is_dry_run {
+                        dry_run(format!("下载：{}", f));
+                        round.changed += 1;
+                        continue;
+                    }
+
+                    tracing::info!("正在下载：{}", f);
+                    let contents = op.read(&f).await?.to_bytes();
+                    verify(&f, &contents, &meta, trust_etag)?;
+
+                    tracing::info!("正在保存：{}", f);
+                    write_atomic(&f, &contents, mode, file.owner.as_deref()).await?;
+                    round.changed += 1;
+                    round.fingerprints.insert(f, fingerprint);
+                }
+            }
+            Direction::Push => {
+                let keys = pattern.local_keys().await?;
+
+                if keys.is_empty() {
+                    tracing::warn!("本地没有匹配的文件：{}", path);
+                }
+
+                for f in keys {
+                    let fingerprint = md5_hex(fs::read(&f).await?);
+
+                    if state.is_fresh(&f, &fingerprint) {
+                        tracing::debug!("没有变化：{}", f);
+                        continue;
+                    }
+
+                    if is_dry_run {
+                        dry_run(format!("上传：{}", f));
+                    } else {
+                        push.push_str(&f).await?;
+                        pushed.insert(f, fingerprint);
+                    }
+                }
+            }
+        }
+    }
+
+    // 全部上传成功后才记录上传的指纹，否则下一轮会重试
+    push.join().await?;
+    round.fingerprints.extend(pushed);
+    Ok(())
+}
+
+async fn run_on_change(config: &SyncConfig, is_dry_run: bool) -> Result<(), anyhow::Error> {
+    let Some((program, args)) = config.on_change.as_ref().and_then(|c| c.split_first()) else {
+        return Ok(());
+    };
+    let command_line = config.on_change.as_ref().unwrap().join(" ");
+
+    if is_dry_run {
+        dry_run(format!("执行：{}", command_line));
+        Ok(())
+    } else {
+        tracing::info!("正在执行：{}", command_line);
+        spawn_command(Command::new(program).args(args), program).await
+    }
+}
+
+fn fingerprint(meta: &Metadata) -> String {
+    match meta.etag() {
+        Some(etag) => etag.trim_matches('"').to_owned(),
+        None => format!(
+            "{}-{}",
+            meta.content_length(),
+            meta.last_modified()
+                .map(|t| t.timestamp_millis())
+                .unwrap_or_default()
+        ),
+    }
+}
+
+const STATE_FILE: &str = ".gitops-sync-state.json";
+
+#[derive(Deserialize, Serialize, Default)]
+struct SyncState {
+    #[serde(default)]
+    files: BTreeMap<String, String>,
+    // 本地文件已经更新，但on_change还没有执行成功
+    #[serde(default)]
+    pending_hook: bool,
+}
+
+impl SyncState {
+    // 状态文件只是缓存，无法解析时重新完整同步一次
+    async fn load(path: &str) -> Result<Self, anyhow::Error> {
+        match fs::read(path).await {
+            Ok(contents) => Ok(serde_json::from_slice(&contents).unwrap_or_else(|err| {
+                tracing::warn!("无法解析{}，将重新同步：{}", path, err);
+                Default::default()
+            })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Default::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save(&self, path: &str) -> Result<(), anyhow::Error> {
+        write_atomic(path, &serde_json::to_vec_pretty(self)?, None, None).await
+    }
 
-    tracing::info!("正在初始化OSS Operator……");
-    let op = Operator::new(
-        Oss::default()
-            .root(&config.root)
-            .access_key_id(config.access_key_id.as_ref().unwrap())
-            .access_key_secret(config.access_key_secret.as_ref().unwrap())
-            .bucket(&config.bucket)
-            .endpoint(&config.endpoint),
-    )?
-    .finish();
+    fn is_fresh(&self, key: &str, fingerprint: &str) -> bool {
+        self.files.get(key).is_some_and(|f| f == fingerprint)
+    }
+}
+
+fn verify(
+    key: &str,
+    contents: &[u8],
+    meta: &Metadata,
+    trust_etag: bool,
+) -> Result<(), anyhow::Error> {
+    if contents.len() as u64 != meta.content_length() {
+        return Err(anyhow::anyhow!(
+            "{}大小校验失败！期望：{}，实际：{}",
+            key,
+            meta.content_length(),
+            contents.len()
+        ));
+    }
 
-    for f in &config.files {
-        tracing::info!("正在下载：{}", f);
-        let contents = op.read(f).await?.to_bytes();
+    if let Some(expected) = content_md5(meta, trust_etag) {
+        let md5 = md5_hex(contents);
 
-        tracing::info!("正在保存：{}", f);
-        fs::write(f, contents).await?;
+        if !expected.eq_ignore_ascii_case(&md5) {
+            return Err(anyhow::anyhow!(
+                "{}MD5校验失败！期望：{}，实际：{}",
+                key,
+                expected,
+                md5
+            ));
+        }
     }
 
     Ok(())
 }
+
+// 只在存储的ETag确实是MD5时使用ETag，否则（包括分片上传）改用上传时写入的自定义元数据，都没有时只校验大小
+fn content_md5(meta: &Metadata, trust_etag: bool) -> Option<&str> {
+    meta.etag()
+        .filter(|_| trust_etag)
+        .map(|e| e.trim_matches('"'))
+        .filter(|e| e.len() == 32 && e.chars().all(|c| c.is_ascii_hexdigit()))
+        .or_else(|| {
+            meta.user_metadata()
+                .and_then(|m| m.get(CONTENT_MD5))
+                .map(String::as_str)
+        })
+}
+
+async fn is_local_fresh(
+    key: &str,
+    meta: &Metadata,
+    trust_etag: bool,
+) -> Result<bool, anyhow::Error> {
+    let Some(expected) = content_md5(meta, trust_etag) else {
+        return Ok(false);
+    };
+
+    match fs::read(key).await {
+        Ok(contents) => Ok(contents.len() as u64 == meta.content_length()
+            && md5_hex(contents).eq_ignore_ascii_case(expected)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn write_atomic(
+    path: &str,
+    contents: &[u8],
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let path = Path::new(path);
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let name = path
+        .file_name()
+        .ok_or(anyhow::anyhow!("非法路径：{}", path.display()))?;
+    let tmp = parent.join(format!(".{}.gitops-tmp", name.to_string_lossy()));
+
+    if !parent.as_os_str().is_empty() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let result = async {
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        set_mode_owner(&tmp, mode, owner).await?;
+        Ok::<(), anyhow::Error>(fs::rename(&tmp, path).await?)
+    }
+    .await;
+
+    if result.is_err() {
+        fs::remove_file(&tmp).await.ok();
+    }
+
+    result
+}
+
+#[cfg(not(windows))]
+async fn set_mode_owner(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+    }
+
+    if let Some(owner) = owner {
+        spawn_command(Command::new("chown").arg(owner).arg(path), "chown").await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn set_mode_owner(
+    _path: &Path,
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    if mode.is_some() || owner.is_some() {
+        tracing::warn!("Windows不支持设置mode和owner，已忽略");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opendal::EntryMode;
+
+    #[test]
+    fn etag_is_only_trusted_as_md5_when_storage_says_so() {
+        let contents = b"hello";
+        let md5 = md5_hex(contents);
+        // SSE-KMS等情况下ETag与内容无关
+        let mut meta = Metadata::new(EntryMode::FILE)
+            .with_content_length(contents.len() as u64)
+            .with_etag(format!("\"{}\"", "0".repeat(32)));
+
+        assert!(verify("a", contents, &meta, true).is_err());
+        assert!(verify("a", contents, &meta, false).is_ok());
+        assert!(verify("a", b"hell", &meta, false).is_err());
+
+        meta.with_user_metadata([(CONTENT_MD5.to_owned(), md5)].into());
+        assert!(verify("a", contents, &meta, false).is_ok());
+        assert!(verify("a", b"jello", &meta, false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_on_change_is_retried_without_new_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("Caddyfile");
+        let log = dir.path().join("hook.log");
+        let key = target.to_str().unwrap();
+        let storage =
+            StorageConfig::resolve(&Some(toml::from_str(r#"kind = "memory""#).unwrap())).unwrap();
+        let op = storage.operator("ops", None).unwrap();
+        let config = |hook: &str| -> SyncConfig {
+            toml::from_str(&format!(
+                r#"
+                bucket = "b"
+                endpoint = "e"
+                root = "ops"
+                files = [{:?}]
+                on_change = ["sh", "-c", {:?}]
+                "#,
+                key, hook
+            ))
+            .unwrap()
+        };
+        let hook = format!("echo ran >> '{}'", log.display());
+        let hook_runs = || async { fs::read_to_string(&log).await.unwrap_or_default() };
+        let mut state = SyncState::default();
+
+        op.write(key, "config").await.unwrap();
+        let result = sync_round(&op, &storage, &config("exit 1"), &mut state, false).await;
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&target).await.unwrap(), "config");
+        assert!(state.pending_hook);
+
+        // 远端没有变化，但上一轮的on_change失败了，需要重试
+        sync_round(&op, &storage, &config(&hook), &mut state, false)
+            .await
+            .unwrap();
+
+        assert!(!state.pending_hook);
+        assert_eq!(hook_runs().await, "ran\n");
+
+        sync_round(&op, &storage, &config(&hook), &mut state, false)
+            .await
+            .unwrap();
+
+        assert_eq!(hook_runs().await, "ran\n");
+    }
+}
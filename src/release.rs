@@ -0,0 +1,111 @@
+use super::storage::{Bucket, StorageConfig};
+use opendal::{ErrorKind, Operator};
+
+const CURRENT: &str = "current";
+const RELEASES: &str = "releases/";
+
+pub struct Releases<'a> {
+    storage: &'a StorageConfig,
+    root: String,
+    bucket: Option<Bucket>,
+    op: Operator,
+}
+
+impl<'a> Releases<'a> {
+    pub fn new(
+        storage: &'a StorageConfig,
+        root: &str,
+        bucket: Option<Bucket>,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            op: storage.operator(root, bucket.as_ref())?,
+            storage,
+            root: root.trim_end_matches('/').into(),
+            bucket,
+        })
+    }
+
+    // 精确到毫秒，同一秒内的两次部署（例如draft与prod共用root时）不会共用同一个版本
+    pub fn new_id() -> String {
+        chrono::Local::now().format("%Y%m%d%H%M%S%3f").to_string()
+    }
+
+    pub async fn exists(&self, id: &str) -> Result<bool, anyhow::Error> {
+        Ok(self.list().await?.iter().any(|r| r == id))
+    }
+
+    pub fn operator(&self, id: &str) -> Result<Operator, anyhow::Error> {
+        self.storage.operator(
+            &format!("{}/{}{}", self.root, RELEASES, id),
+            self.bucket.as_ref(),
+        )
+    }
+
+    pub async fn list(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut ids = self
+            .op
+            .list(RELEASES)
+            .await?
+            .into_iter()
+            .filter(|e| e.metadata().is_dir() && e.path() != RELEASES)
+            .map(|e| e.name().trim_end_matches('/').to_owned())
+            .collect::<Vec<String>>();
+
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub async fn current(&self) -> Result<Option<String>, anyhow::Error> {
+        match self.op.read(CURRENT).await {
+            Ok(buf) => Ok(Some(String::from_utf8(buf.to_vec())?.trim().to_owned())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn point_to(&self, id: &str) -> Result<(), anyhow::Error> {
+        if !self.exists(id).await? {
+            return Err(anyhow::anyhow!("找不到发布版本：{}", id));
+        }
+
+        tracing::info!("正在切换当前发布版本至：{}", id);
+        Ok(self.op.write(CURRENT, id.to_owned()).await?)
+    }
+
+    pub async fn previous(&self) -> Result<String, anyhow::Error> {
+        let ids = self.list().await?;
+        let current = self
+            .current()
+            .await?
+            .ok_or(anyhow::anyhow!("尚未发布过任何版本！"))?;
+        let pos = ids
+            .iter()
+            .position(|r| r == &current)
+            .ok_or(anyhow::anyhow!("当前发布版本{}已不存在！", current))?;
+
+        match pos {
+            0 => Err(anyhow::anyhow!("{}之前没有可以回滚的版本！", current)),
+            _ => Ok(ids[pos - 1].clone()),
+        }
+    }
+
+    pub async fn prune(&self, retain: usize) -> Result<(), anyhow::Error> {
+        let ids = self.list().await?;
+        let current = self.current().await?;
+
+        if ids.len() <= retain {
+            return Ok(());
+        }
+
+        for id in &ids[..ids.len() - retain] {
+            if Some(id) == current.as_ref() {
+                continue;
+            }
+
+            tracing::info!("正在清理旧发布版本：{}", id);
+            self.op.remove_all(&format!("{}{}/", RELEASES, id)).await?;
+        }
+
+        Ok(())
+    }
+}
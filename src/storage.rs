@@ -0,0 +1,419 @@
+use super::{opendal_fs::UploadConfig, utils::env_var};
+use backon::ExponentialBuilder;
+use opendal::{
+    layers::{MimeGuessLayer, RetryInterceptor, RetryLayer},
+    raw::{adapters::typed_kv, Access},
+    services::{Fs, Oss, Webdav, S3},
+    Builder, Operator, OperatorBuilder, Scheme,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct StorageConfig {
+    #[serde(default)]
+    kind: StorageKind,
+    region: Option<String>,
+    endpoint: Option<String>,
+    root: Option<String>,
+    access_key_id: Option<String>,
+    access_key_secret: Option<String>,
+    #[serde(default)]
+    upload: UploadConfig,
+    #[serde(default)]
+    retry: RetryConfig,
+    #[serde(skip)]
+    memory: SharedMemory,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    max_attempts: usize,
+    min_delay_ms: u64,
+    max_delay_ms: u64,
+    factor: f32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            min_delay_ms: 500,
+            max_delay_ms: 30_000,
+            factor: 2.0,
+        }
+    }
+}
+
+impl RetryConfig {
+    // 给不经过opendal的请求使用，与RetryLayer的策略一致
+    pub fn backoff(&self) -> ExponentialBuilder {
+        ExponentialBuilder::default()
+            .with_max_times(self.max_attempts.saturating_sub(1))
+            .with_min_delay(Duration::from_millis(self.min_delay_ms))
+            .with_max_delay(Duration::from_millis(self.max_delay_ms))
+            .with_factor(self.factor)
+            .with_jitter()
+    }
+
+    fn layer(&self) -> RetryLayer<TracingRetryInterceptor> {
+        RetryLayer::new()
+            .with_max_times(self.max_attempts.saturating_sub(1))
+            .with_min_delay(Duration::from_millis(self.min_delay_ms))
+            .with_max_delay(Duration::from_millis(self.max_delay_ms))
+            .with_factor(self.factor)
+            .with_jitter()
+            .with_notify(TracingRetryInterceptor)
+    }
+}
+
+struct TracingRetryInterceptor;
+
+impl RetryInterceptor for TracingRetryInterceptor {
+    fn intercept(&self, err: &opendal::Error, dur: Duration) {
+        tracing::warn!("操作失败，将在{:?}后重试：{}", dur, err);
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    #[default]
+    Oss,
+    S3,
+    Fs,
+    Memory,
+    Webdav,
+}
+
+impl StorageKind {
+    // 除最后一片外，分片的最小尺寸
+    fn min_chunk_size(&self) -> usize {
+        match self {
+            Self::S3 => 5 * 1024 * 1024,
+            Self::Oss => 100 * 1024,
+            Self::Fs | Self::Memory | Self::Webdav => 1,
+        }
+    }
+}
+
+impl Display for StorageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// opendal的Memory每次build都会新建一个BTreeMap，不同root的Operator之间看不到彼此的数据，
+// 这里让同一份StorageConfig构建出的所有Operator共用一个BTreeMap
+#[derive(Clone, Default, Debug)]
+struct SharedMemory(Arc<Mutex<BTreeMap<String, typed_kv::Value>>>);
+
+impl typed_kv::Adapter for SharedMemory {
+    fn info(&self) -> typed_kv::Info {
+        typed_kv::Info::new(
+            Scheme::Memory,
+            &format!("{:?}", Arc::as_ptr(&self.0)),
+            typed_kv::Capability {
+                get: true,
+                set: true,
+                delete: true,
+                scan: true,
+            },
+        )
+    }
+
+    async fn get(&self, path: &str) -> opendal::Result<Option<typed_kv::Value>> {
+        self.blocking_get(path)
+    }
+
+    fn blocking_get(&self, path: &str) -> opendal::Result<Option<typed_kv::Value>> {
+        Ok(self.0.lock().unwrap().get(path).cloned())
+    }
+
+    async fn set(&self, path: &str, value: typed_kv::Value) -> opendal::Result<()> {
+        self.blocking_set(path, value)
+    }
+
+    fn blocking_set(&self, path: &str, value: typed_kv::Value) -> opendal::Result<()> {
+        self.0.lock().unwrap().insert(path.into(), value);
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> opendal::Result<()> {
+        self.blocking_delete(path)
+    }
+
+    fn blocking_delete(&self, path: &str) -> opendal::Result<()> {
+        self.0.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    async fn scan(&self, path: &str) -> opendal::Result<Vec<String>> {
+        self.blocking_scan(path)
+    }
+
+    fn blocking_scan(&self, path: &str) -> opendal::Result<Vec<String>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .range(path.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(path))
+            .cloned()
+            .collect())
+    }
+}
+
+pub struct Bucket {
+    pub name: String,
+    pub endpoint: Option<String>,
+}
+
+impl Bucket {
+    pub fn new(name: &str, endpoint: &str) -> Self {
+        Self {
+            name: name.into(),
+            endpoint: Some(endpoint.into()),
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn resolve(config: &Option<Self>) -> Result<Self, anyhow::Error> {
+        let mut storage = config.clone().unwrap_or_default();
+
+        match storage.kind {
+            StorageKind::Oss => {
+                storage.access_key_id.replace(env_var("OSS_ACCESS_KEY_ID")?);
+                storage
+                    .access_key_secret
+                    .replace(env_var("OSS_ACCESS_KEY_SECRET")?);
+            }
+            StorageKind::S3 => {
+                storage.access_key_id.replace(env_var("S3_ACCESS_KEY_ID")?);
+                storage
+                    .access_key_secret
+                    .replace(env_var("S3_SECRET_ACCESS_KEY")?);
+            }
+            StorageKind::Webdav => {
+                storage.access_key_id = env_var("WEBDAV_USERNAME").ok();
+                storage.access_key_secret = env_var("WEBDAV_PASSWORD").ok();
+            }
+            StorageKind::Fs | StorageKind::Memory => (),
+        }
+
+        storage.upload.validate(storage.kind.min_chunk_size())?;
+        Ok(storage)
+    }
+
+    pub fn upload(&self) -> &UploadConfig {
+        &self.upload
+    }
+
+    pub fn retry(&self) -> &RetryConfig {
+        &self.retry
+    }
+
+    // OSS普通上传的ETag就是内容的MD5；S3开启SSE-KMS/SSE-C后不是，其他存储没有ETag或格式不定
+    pub fn etag_is_md5(&self) -> bool {
+        matches!(self.kind, StorageKind::Oss)
+    }
+
+    // 例如prefix为OSS_DRAFT时，读取OSS_DRAFT_BUCKET和OSS_DRAFT_ENDPOINT
+    pub fn bucket_from_env(&self, prefix: &str) -> Result<Option<Bucket>, anyhow::Error> {
+        let bucket = format!("{}_BUCKET", prefix);
+        let endpoint = format!("{}_ENDPOINT", prefix);
+
+        Ok(match self.kind {
+            StorageKind::Oss | StorageKind::S3 => Some(Bucket {
+                name: env_var(bucket)?,
+                endpoint: Some(env_var(endpoint)?),
+            }),
+            StorageKind::Fs | StorageKind::Memory | StorageKind::Webdav => {
+                env_var(bucket).ok().map(|name| Bucket {
+                    name,
+                    endpoint: None,
+                })
+            }
+        })
+    }
+
+    pub fn operator(&self, root: &str, bucket: Option<&Bucket>) -> Result<Operator, anyhow::Error> {
+        tracing::info!("正在初始化{} Operator……", self.kind);
+
+        match self.kind {
+            StorageKind::Oss => {
+                let bucket = self.require_bucket(bucket)?;
+                self.finish(
+                    Oss::default()
+                        .root(root)
+                        .access_key_id(self.require(&self.access_key_id, "access_key_id")?)
+                        .access_key_secret(
+                            self.require(&self.access_key_secret, "access_key_secret")?,
+                        )
+                        .bucket(&bucket.name)
+                        .endpoint(self.require_endpoint(bucket)?),
+                )
+            }
+            StorageKind::S3 => {
+                let bucket = self.require_bucket(bucket)?;
+                self.finish(
+                    S3::default()
+                        .root(root)
+                        .access_key_id(self.require(&self.access_key_id, "access_key_id")?)
+                        .secret_access_key(
+                            self.require(&self.access_key_secret, "access_key_secret")?,
+                        )
+                        .region(self.region.as_deref().unwrap_or("us-east-1"))
+                        .bucket(&bucket.name)
+                        .endpoint(self.require_endpoint(bucket)?),
+                )
+            }
+            StorageKind::Fs => {
+                let base = self.require(&self.root, "root")?;
+                self.finish(Fs::default().root(&join_root(base, bucket, root)))
+            }
+            StorageKind::Memory => Ok(self.layer(OperatorBuilder::new(
+                typed_kv::Backend::new(self.memory.clone())
+                    .with_root(&join_root("/", bucket, root)),
+            ))),
+            StorageKind::Webdav => {
+                let mut webdav = Webdav::default()
+                    .endpoint(self.require(&self.endpoint, "endpoint")?)
+                    .root(&join_root(
+                        self.root.as_deref().unwrap_or("/"),
+                        bucket,
+                        root,
+                    ));
+
+                if let Some(username) = &self.access_key_id {
+                    webdav = webdav.username(username);
+                }
+
+                if let Some(password) = &self.access_key_secret {
+                    webdav = webdav.password(password);
+                }
+
+                self.finish(webdav)
+            }
+        }
+    }
+
+    fn finish(&self, builder: impl Builder) -> Result<Operator, anyhow::Error> {
+        Ok(self.layer(Operator::new(builder)?))
+    }
+
+    fn layer(&self, builder: OperatorBuilder<impl Access>) -> Operator {
+        builder
+            .layer(MimeGuessLayer::default())
+            .layer(self.retry.layer())
+            .finish()
+    }
+
+    fn require<'a>(&self, field: &'a Option<String>, name: &str) -> Result<&'a str, anyhow::Error> {
+        field
+            .as_deref()
+            .ok_or(anyhow::anyhow!("存储类型{}缺少{}！", self.kind, name))
+    }
+
+    fn require_bucket<'a>(&self, bucket: Option<&'a Bucket>) -> Result<&'a Bucket, anyhow::Error> {
+        bucket.ok_or(anyhow::anyhow!("存储类型{}缺少bucket！", self.kind))
+    }
+
+    fn require_endpoint<'a>(&'a self, bucket: &'a Bucket) -> Result<&'a str, anyhow::Error> {
+        match &self.endpoint {
+            Some(endpoint) => Ok(endpoint),
+            None => self.require(&bucket.endpoint, "endpoint"),
+        }
+    }
+}
+
+fn join_root(base: &str, bucket: Option<&Bucket>, root: &str) -> String {
+    let mut path = base.trim_end_matches('/').to_owned();
+
+    for part in bucket.map(|b| b.name.as_str()).into_iter().chain([root]) {
+        let part = part.trim_matches('/');
+        if !part.is_empty() {
+            path.push('/');
+            path.push_str(part);
+        }
+    }
+
+    if path.is_empty() {
+        "/".into()
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> StorageConfig {
+        StorageConfig {
+            kind: StorageKind::Memory,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_operators_share_data_across_roots() {
+        let storage = memory();
+        let site = storage.operator("site", None).unwrap();
+        let release = storage.operator("site/releases/1", None).unwrap();
+
+        release.write("index.html", "hello").await.unwrap();
+
+        let data = site.read("releases/1/index.html").await.unwrap();
+        assert_eq!(data.to_vec(), b"hello");
+        assert_eq!(
+            site.list("releases/").await.unwrap()[0].path(),
+            "releases/1/"
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_is_shared_by_resolved_clones() {
+        let storage = StorageConfig::resolve(&Some(memory())).unwrap();
+        let bucket = Bucket {
+            name: "prod".into(),
+            endpoint: None,
+        };
+
+        storage
+            .operator("/", Some(&bucket))
+            .unwrap()
+            .write("a", "1")
+            .await
+            .unwrap();
+
+        let other = storage.clone().operator("prod", None).unwrap();
+        assert!(other.is_exist("a").await.unwrap());
+    }
+
+    #[test]
+    fn chunk_size_is_validated() {
+        let config = |chunk_size: usize| {
+            toml::from_str::<StorageConfig>(&format!(
+                "kind = \"memory\"\n[upload]\nchunk_size = {}",
+                chunk_size
+            ))
+            .unwrap()
+        };
+
+        assert!(StorageConfig::resolve(&Some(config(0))).is_err());
+        assert!(StorageConfig::resolve(&Some(config(1))).is_ok());
+        assert!(config(1024 * 1024)
+            .upload
+            .validate(StorageKind::S3.min_chunk_size())
+            .is_err());
+    }
+}
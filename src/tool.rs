@@ -0,0 +1,336 @@
+use super::{
+    asset::{AssetConfig, Tool},
+    tool_cache::{ToolCache, DEFAULT_RETAIN},
+    utils::{dry_run, retain_decimal_places, unzip, unzip_dir, verify_checksum},
+    version, Config,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::process::Command;
+
+const DEFAULT_PROBE_REGEX: &str = r"(\d+\.\d+\.\d+)";
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct ToolConfig {
+    pub version: String,
+    // GitHub仓库（owner/repo），用于解析"latest"或semver范围
+    repo: Option<String>,
+    // 支持{version}、{os}、{arch}、{asset}
+    url: Option<String>,
+    checksums: Option<String>,
+    // 压缩包中可执行文件的名称前缀，默认为工具名
+    entry: Option<String>,
+    // 解压压缩包中的整个目录（例如dart-sass），此时entry为可执行文件在目录中的相对路径
+    dir: Option<String>,
+    probe: Option<Vec<String>>,
+    probe_regex: Option<String>,
+    #[serde(default)]
+    asset: AssetConfig,
+    retain: Option<usize>,
+}
+
+impl ToolConfig {
+    pub fn with_version(&self, version: &str) -> Self {
+        Self {
+            version: version.into(),
+            ..self.clone()
+        }
+    }
+
+    pub fn github(repo: &str, checksums: &str) -> Self {
+        let base = format!("https://github.com/{}/releases/download/v{{version}}", repo);
+        Self {
+            repo: Some(repo.into()),
+            url: Some(format!("{}/{{asset}}", base)),
+            checksums: Some(format!("{}/{}", base, checksums)),
+            ..Default::default()
+        }
+    }
+
+    pub fn cache(&self, name: &str) -> Result<ToolCache, anyhow::Error> {
+        ToolCache::new(name, self.entry.as_deref().unwrap_or(name))
+    }
+
+    // 内置工具的默认值，用户配置优先
+    pub fn or(&self, defaults: Self) -> Self {
+        Self {
+            version: self.version.clone(),
+            repo: self.repo.clone().or(defaults.repo),
+            url: self.url.clone().or(defaults.url),
+            checksums: self.checksums.clone().or(defaults.checksums),
+            entry: self.entry.clone().or(defaults.entry),
+            dir: self.dir.clone().or(defaults.dir),
+            probe: self.probe.clone().or(defaults.probe),
+            probe_regex: self.probe_regex.clone().or(defaults.probe_regex),
+            asset: self.asset.clone(),
+            retain: self.retain.or(defaults.retain),
+        }
+    }
+
+    fn render(
+        &self,
+        template: &str,
+        version: &str,
+        builtin: Option<Tool>,
+    ) -> Result<String, anyhow::Error> {
+        let template = if template.contains("{asset}") {
+            template.replace("{asset}", &self.asset.resolve(builtin, version)?)
+        } else {
+            template.to_owned()
+        };
+
+        self.asset.render(&template, version)
+    }
+}
+
+// 已下载到缓存、等待切换的版本
+pub struct Upgrade {
+    pub cache: ToolCache,
+    pub version: String,
+    pub retain: usize,
+}
+
+impl Upgrade {
+    pub async fn activate(&self, is_dry_run: bool) -> Result<(), anyhow::Error> {
+        if is_dry_run {
+            dry_run(format!("切换{}至：{}", self.cache.name(), self.version));
+            return Ok(());
+        }
+
+        self.cache.activate(&self.version).await?;
+        self.cache.prune(self.retain).await
+    }
+}
+
+pub async fn prepare(
+    config: &Config,
+    name: &str,
+    tool: &ToolConfig,
+    builtin: Option<Tool>,
+) -> Result<Option<Upgrade>, anyhow::Error> {
+    let version = version::resolve(config, name, tool.repo.as_deref(), &tool.version).await?;
+
+    tracing::info!("请求的{}版本是：{}", name, version);
+    tracing::info!("正在校验现有{}版本……", name);
+
+    let cache = tool.cache(name)?;
+    let installed = probe(name, &cache.link(), tool).await?;
+
+    if installed.as_deref() == Some(version.as_str()) {
+        tracing::info!("现有{}版本匹配！将跳过下载", name);
+        return Ok(None);
+    }
+
+    match &installed {
+        Some(installed) => {
+            tracing::info!("现有{}版本{}不匹配，准备更新{}", name, installed, name);
+            // 手动部署的版本切换前先放进缓存，之后才能回滚到它
+            if config.dry_run {
+                if cache.can_import().await? {
+                    dry_run(format!("导入现有{} {}至缓存", name, installed));
+                }
+            } else {
+                cache.import(installed).await?;
+            }
+        }
+        None => tracing::info!("{}不存在，准备下载{}", name, name),
+    }
+
+    let upgrade = Upgrade {
+        retain: tool.retain.unwrap_or(DEFAULT_RETAIN),
+        version,
+        cache,
+    };
+    let version = &upgrade.version;
+
+    if upgrade.cache.has(version) {
+        tracing::info!("缓存中已有{} {}，将跳过下载", name, version);
+        return Ok(Some(upgrade));
+    }
+
+    let url = tool.render(
+        tool.url
+            .as_deref()
+            .ok_or(anyhow::anyhow!("未配置{}的url！", name))?,
+        version,
+        builtin,
+    )?;
+    let file_name = url.rsplit('/').next().unwrap_or_default().to_owned();
+    let checksums = match &tool.checksums {
+        Some(checksums) => Some(tool.render(checksums, version, builtin)?),
+        None => None,
+    };
+
+    if config.dry_run {
+        dry_run(format!("下载：{}", url));
+        if let Some(checksums) = &checksums {
+            dry_run(format!("校验：{}", checksums));
+        }
+        dry_run(format!(
+            "保存至：{}",
+            upgrade.cache.version_dir(version).display()
+        ));
+        return Ok(Some(upgrade));
+    }
+
+    tracing::info!("正在GET：{}", url);
+    let bytes = reqwest::get(&url)
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    if bytes.is_empty() {
+        return Err(anyhow::anyhow!("未下载任何内容！"));
+    }
+
+    tracing::info!(
+        "已下载：{} MB",
+        retain_decimal_places(bytes.len() as f64 / 1024.0 / 1024.0, 3)
+    );
+
+    match &checksums {
+        Some(checksums) => verify_checksum(&bytes, checksums, &file_name).await?,
+        None => tracing::warn!("未配置{}的checksums，跳过校验！", name),
+    }
+
+    if let Some(dir) = &tool.dir {
+        if !is_archive(&file_name) {
+            return Err(anyhow::anyhow!(
+                "{}不是压缩包，无法解压{}目录！",
+                file_name,
+                dir
+            ));
+        }
+
+        tracing::info!("正在解压{}目录……", dir);
+        upgrade
+            .cache
+            .install_dir(version, |dest| unzip_dir(&bytes, dir, dest))
+            .await?;
+        return Ok(Some(upgrade));
+    }
+
+    let contents = if is_archive(&file_name) {
+        tracing::info!("正在解压……");
+        let (name, contents) = unzip(&bytes, tool.entry.as_deref().unwrap_or(name))?;
+        tracing::info!("已解压：{:?}", name);
+        contents
+    } else {
+        bytes.into()
+    };
+
+    tracing::info!(
+        "文件大小：{} MB",
+        retain_decimal_places(contents.len() as f64 / 1024.0 / 1024.0, 3)
+    );
+    upgrade.cache.install(version, contents).await?;
+
+    Ok(Some(upgrade))
+}
+
+// 返回现有版本，不存在时返回None
+async fn probe(
+    name: &str,
+    link: &Path,
+    tool: &ToolConfig,
+) -> Result<Option<String>, anyhow::Error> {
+    let probe = tool.probe.clone().unwrap_or(vec!["version".into()]);
+    let regex = Regex::new(tool.probe_regex.as_deref().unwrap_or(DEFAULT_PROBE_REGEX))?;
+
+    match Command::new(link).args(&probe).output().await {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let found = regex
+                .captures(&stdout)
+                .and_then(|c| c.get(1).or(c.get(0)))
+                .map(|m| m.as_str().to_owned());
+
+            Ok(Some(found.ok_or(anyhow::anyhow!(
+                "无法从{} {}的输出中识别版本！",
+                name,
+                probe.join(" ")
+            ))?))
+        }
+        Ok(output) => Err(anyhow::anyhow!(
+            "{} {}执行失败！退出码：{}",
+            name,
+            probe.join(" "),
+            if let Some(code) = output.status.code() {
+                code.to_string()
+            } else {
+                "None".into()
+            }
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+fn is_archive(file_name: &str) -> bool {
+    [".tar.gz", ".tgz", ".zip"]
+        .iter()
+        .any(|ext| file_name.ends_with(ext))
+}
+
+pub async fn upgrade(config: &Config, name: Option<&str>) -> Result<(), anyhow::Error> {
+    for (name, tool) in select(config, name)? {
+        tracing::info!("================");
+        if let Some(upgrade) = prepare(config, name, tool, None).await? {
+            upgrade.activate(config.dry_run).await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn rollback(config: &Config, name: Option<&str>) -> Result<(), anyhow::Error> {
+    for (name, tool) in select(config, name)? {
+        rollback_one(config, name, tool).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn rollback_one(
+    config: &Config,
+    name: &str,
+    tool: &ToolConfig,
+) -> Result<(), anyhow::Error> {
+    let cache = tool.cache(name)?;
+    let version = cache.previous().await?;
+
+    if config.dry_run {
+        dry_run(format!("切换{}至：{}", name, version));
+    } else {
+        cache.activate(&version).await?;
+    }
+
+    version::pin_rollback(config, name, &tool.version, &version).await?;
+    tracing::warn!(
+        "已回滚{}至{}，修改配置中的版本后才会再次升级",
+        name,
+        version
+    );
+    Ok(())
+}
+
+fn select<'a>(
+    config: &'a Config,
+    name: Option<&'a str>,
+) -> Result<Vec<(&'a str, &'a ToolConfig)>, anyhow::Error> {
+    match name {
+        Some(name) => Ok(vec![(
+            name,
+            config
+                .tools
+                .get(name)
+                .ok_or(anyhow::anyhow!("找不到[tools.{}]字段！", name))?,
+        )]),
+        None => Ok(config
+            .tools
+            .iter()
+            .map(|(name, tool)| (name.as_str(), tool))
+            .collect()),
+    }
+}
@@ -0,0 +1,242 @@
+use semver::Version;
+use std::{
+    cmp::Ordering,
+    env::{consts::EXE_SUFFIX, current_exe},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+const TOOLS: &str = "tools";
+const CURRENT: &str = "current";
+pub const DEFAULT_RETAIN: usize = 3;
+
+// 每个版本装在tools/<name>/<version>/下，可执行文件旁的<entry>指向当前版本
+pub struct ToolCache {
+    name: String,
+    // 可执行文件在版本目录中的相对路径（含EXE_SUFFIX）
+    entry: PathBuf,
+    exe_dir: PathBuf,
+    dir: PathBuf,
+}
+
+impl ToolCache {
+    pub fn new(name: &str, entry: &str) -> Result<Self, anyhow::Error> {
+        let exe_dir = current_exe()?
+            .parent()
+            .ok_or(anyhow::anyhow!("无法获取可执行文件所在目录！"))?
+            .to_owned();
+
+        Ok(Self {
+            name: name.into(),
+            entry: exe_path(entry),
+            dir: exe_dir.join(TOOLS).join(name),
+            exe_dir,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn link(&self) -> PathBuf {
+        self.exe_dir
+            .join(self.entry.file_name().unwrap_or_default())
+    }
+
+    pub fn version_dir(&self, version: &str) -> PathBuf {
+        self.dir.join(version)
+    }
+
+    // 以可执行文件为准，只有目录的版本视为不完整
+    pub fn has(&self, version: &str) -> bool {
+        self.version_dir(version).join(&self.entry).is_file()
+    }
+
+    pub async fn versions(&self) -> Result<Vec<String>, anyhow::Error> {
+        let mut versions = Vec::new();
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(versions),
+            Err(err) => return Err(err.into()),
+        };
+
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                versions.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+
+        versions.sort_by(|a, b| compare_versions(a, b));
+        Ok(versions)
+    }
+
+    pub async fn current(&self) -> Result<Option<String>, anyhow::Error> {
+        match fs::read_to_string(self.dir.join(CURRENT)).await {
+            Ok(version) => Ok(Some(version.trim().to_owned())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn install(&self, version: &str, contents: Vec<u8>) -> Result<(), anyhow::Error> {
+        let tmp = self.staging().await?;
+        let path = tmp.join(&self.entry);
+
+        fs::create_dir_all(path.parent().unwrap_or(&tmp)).await?;
+        fs::write(&path, contents).await?;
+        self.commit(&tmp, version).await
+    }
+
+    pub async fn install_dir(
+        &self,
+        version: &str,
+        unpack: impl FnOnce(&Path) -> Result<(), anyhow::Error>,
+    ) -> Result<(), anyhow::Error> {
+        let tmp = self.staging().await?;
+
+        unpack(&tmp)?;
+        self.commit(&tmp, version).await
+    }
+
+    // 所有安装都先写到临时目录，完整写入后再rename为版本目录，中途失败不会留下不完整的版本
+    async fn staging(&self) -> Result<PathBuf, anyhow::Error> {
+        let tmp = self.dir.with_file_name(format!("{}.tmp", self.name));
+
+        fs::remove_dir_all(&tmp).await.ok();
+        fs::create_dir_all(&tmp).await?;
+        Ok(tmp)
+    }
+
+    async fn commit(&self, tmp: &Path, version: &str) -> Result<(), anyhow::Error> {
+        let binary = tmp.join(&self.entry);
+        let dir = self.version_dir(version);
+
+        if !fs::try_exists(&binary).await? {
+            return Err(anyhow::anyhow!("找不到{}！", self.entry.display()));
+        }
+
+        #[cfg(not(windows))]
+        crate::utils::chmod_exec(&binary).await?;
+
+        tracing::info!("正在保存：{}", dir.display());
+        // 之前失败留下的不完整版本
+        fs::remove_dir_all(&dir).await.ok();
+        fs::create_dir_all(&self.dir).await?;
+        Ok(fs::rename(tmp, &dir).await?)
+    }
+
+    // 当前版本不是通过缓存安装的（可执行文件不是链接）时才需要导入
+    pub async fn can_import(&self) -> Result<bool, anyhow::Error> {
+        if self.current().await?.is_some() {
+            return Ok(false);
+        }
+
+        match fs::symlink_metadata(self.link()).await {
+            Ok(meta) => Ok(meta.is_file()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // 把手动部署的版本放进缓存并记为当前版本，切换后仍能回滚到它
+    pub async fn import(&self, version: &str) -> Result<(), anyhow::Error> {
+        if !self.can_import().await? || self.has(version) {
+            return Ok(());
+        }
+
+        let tmp = self.staging().await?;
+        let path = tmp.join(&self.entry);
+
+        tracing::info!("正在把现有的{} {}导入缓存……", self.name, version);
+        fs::create_dir_all(path.parent().unwrap_or(&tmp)).await?;
+        fs::copy(self.link(), path).await?;
+        self.commit(&tmp, version).await?;
+        Ok(fs::write(self.dir.join(CURRENT), version).await?)
+    }
+
+    pub async fn activate(&self, version: &str) -> Result<(), anyhow::Error> {
+        let binary = self.version_dir(version).join(&self.entry);
+
+        if !fs::try_exists(&binary).await? {
+            return Err(anyhow::anyhow!("缓存中找不到{} {}！", self.name, version));
+        }
+
+        tracing::info!("正在切换{}至：{}", self.name, version);
+        replace_link(&binary, &self.link()).await?;
+        Ok(fs::write(self.dir.join(CURRENT), version).await?)
+    }
+
+    pub async fn previous(&self) -> Result<String, anyhow::Error> {
+        let versions = self.versions().await?;
+        let current = self
+            .current()
+            .await?
+            .ok_or(anyhow::anyhow!("{}尚未通过缓存安装过任何版本！", self.name))?;
+        let pos = versions
+            .iter()
+            .position(|v| v == &current)
+            .ok_or(anyhow::anyhow!(
+                "{}当前版本{}已不存在！",
+                self.name,
+                current
+            ))?;
+
+        match pos {
+            0 => Err(anyhow::anyhow!("{}之前没有可以回滚的版本！", current)),
+            _ => Ok(versions[pos - 1].clone()),
+        }
+    }
+
+    pub async fn prune(&self, retain: usize) -> Result<(), anyhow::Error> {
+        let versions = self.versions().await?;
+        let current = self.current().await?;
+
+        if versions.len() <= retain {
+            return Ok(());
+        }
+
+        for version in &versions[..versions.len() - retain] {
+            if Some(version) == current.as_ref() {
+                continue;
+            }
+
+            tracing::info!("正在清理旧版本{}：{}", self.name, version);
+            fs::remove_dir_all(self.version_dir(version)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Windows下没有扩展名的entry补上.exe
+fn exe_path(entry: &str) -> PathBuf {
+    match Path::new(entry).extension() {
+        Some(_) => entry.into(),
+        None => format!("{}{}", entry, EXE_SUFFIX).into(),
+    }
+}
+
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (Version::parse(a), Version::parse(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+// 先建临时链接再rename，保证替换是原子的
+#[cfg(not(windows))]
+async fn replace_link(target: &Path, link: &Path) -> Result<(), anyhow::Error> {
+    let tmp = link.with_extension("gitops-tmp");
+
+    fs::remove_file(&tmp).await.ok();
+    fs::symlink(target, &tmp).await?;
+    Ok(fs::rename(&tmp, link).await?)
+}
+
+// Windows创建符号链接需要额外权限，直接拷贝
+#[cfg(windows)]
+async fn replace_link(target: &Path, link: &Path) -> Result<(), anyhow::Error> {
+    fs::copy(target, link).await?;
+    Ok(())
+}
@@ -1,7 +1,11 @@
+use sha2::{Digest, Sha256, Sha512};
 use std::{
     env,
     ffi::{OsStr, OsString},
+    fmt::Display,
     io::Read,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::process::Command;
 
@@ -9,6 +13,16 @@ pub fn env_var(key: impl AsRef<OsStr>) -> Result<String, anyhow::Error> {
     env::var(key.as_ref()).map_err(|_| anyhow::anyhow!("找不到环境变量：{:?}", key.as_ref()))
 }
 
+// 目录名带上进程号与时间戳，多个部署同时运行时互不影响
+pub fn unique_temp_dir(prefix: &str) -> Result<PathBuf, anyhow::Error> {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    Ok(env::temp_dir().join(format!("{}-{}-{}", prefix, std::process::id(), nanos)))
+}
+
+pub fn dry_run(action: impl Display) {
+    tracing::info!("[dry-run] {}", action);
+}
+
 pub fn retain_decimal_places(f: f64, n: i32) -> f64 {
     let power = 10.0f64.powi(n);
     (f * power).round() / power
@@ -70,6 +84,128 @@ pub fn unzip(z: &[u8], e_name: &str) -> Result<(OsString, Vec<u8>), anyhow::Erro
     Err(anyhow::anyhow!("压缩包中未找到{}执行文件！", e_name))
 }
 
+// 压缩包中dir目录下的内容保持相对路径解压到dest
+#[cfg(windows)]
+pub fn unzip_dir(z: &[u8], dir: &str, dest: &Path) -> Result<(), anyhow::Error> {
+    use std::{fs, io::Cursor};
+    use zip::ZipArchive;
+
+    let mut archive = ZipArchive::new(Cursor::new(z))?;
+    let mut found = false;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let path = file
+            .enclosed_name()
+            .ok_or(anyhow::anyhow!("压缩文件路径异常！"))?;
+        let Some(target) = dir_entry_target(&path, dir, dest)? else {
+            continue;
+        };
+
+        if file.is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            std::io::copy(&mut file, &mut fs::File::create(&target)?)?;
+        }
+        found = true;
+    }
+
+    match found {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!("压缩包中未找到{}目录！", dir)),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn unzip_dir(z: &[u8], dir: &str, dest: &Path) -> Result<(), anyhow::Error> {
+    use flate2::read::GzDecoder;
+    use std::fs;
+    use tar::Archive;
+
+    let mut found = false;
+
+    for entry in Archive::new(GzDecoder::new(z)).entries()? {
+        let mut file = entry?;
+        let path = file.path()?.into_owned();
+        let Some(target) = dir_entry_target(&path, dir, dest)? else {
+            continue;
+        };
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // 保留权限位，dart-sass的src/dart等也需要可执行
+        file.unpack(&target)?;
+        found = true;
+    }
+
+    match found {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!("压缩包中未找到{}目录！", dir)),
+    }
+}
+
+// 不在dir下的条目返回None，路径中不允许出现..等跳出dest的部分
+fn dir_entry_target(path: &Path, dir: &str, dest: &Path) -> Result<Option<PathBuf>, anyhow::Error> {
+    let path = path.strip_prefix(".").unwrap_or(path);
+    let Ok(relative) = path.strip_prefix(dir) else {
+        return Ok(None);
+    };
+
+    if relative.as_os_str().is_empty() {
+        return Ok(None);
+    }
+
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(anyhow::anyhow!("压缩文件路径异常：{}", path.display()));
+    }
+
+    Ok(Some(dest.join(relative)))
+}
+
+// checksums.txt每行为“<校验和>  <文件名>”，按长度区分SHA-256与SHA-512
+pub async fn verify_checksum(
+    bytes: &[u8],
+    checksums_url: &str,
+    file_name: &str,
+) -> Result<(), anyhow::Error> {
+    tracing::info!("正在GET：{}", checksums_url);
+    let checksums = reqwest::get(checksums_url)
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let expected = checksums
+        .lines()
+        .filter_map(|l| l.trim().split_once(char::is_whitespace))
+        .find(|(_, name)| name.trim().trim_start_matches('*') == file_name)
+        .map(|(sum, _)| sum.to_ascii_lowercase())
+        .ok_or(anyhow::anyhow!("checksums.txt中找不到{}！", file_name))?;
+    let actual = match expected.len() {
+        64 => format!("{:x}", Sha256::digest(bytes)),
+        128 => format!("{:x}", Sha512::digest(bytes)),
+        _ => return Err(anyhow::anyhow!("无法识别的校验和：{}", expected)),
+    };
+
+    if actual == expected {
+        tracing::info!("{}校验通过！", file_name);
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "{}校验失败！期望：{}，实际：{}",
+            file_name,
+            expected,
+            actual
+        ))
+    }
+}
+
 #[cfg(not(windows))]
 pub async fn chmod_exec(path: impl AsRef<std::path::Path>) -> Result<(), anyhow::Error> {
     tracing::info!("正在设置执行权限……");
@@ -77,6 +213,27 @@ pub async fn chmod_exec(path: impl AsRef<std::path::Path>) -> Result<(), anyhow:
     Ok(tokio::fs::set_permissions(path, Permissions::from_mode(0o755)).await?)
 }
 
+// 只显示程序名，并将secrets替换为****
+pub fn command_line(cmd: &Command, secrets: &[&str]) -> String {
+    let cmd = cmd.as_std();
+    let program = Path::new(cmd.get_program())
+        .file_name()
+        .unwrap_or(cmd.get_program());
+    let mut line = [program]
+        .into_iter()
+        .chain(cmd.get_args())
+        .collect::<Vec<&OsStr>>()
+        .join(" ".as_ref())
+        .to_string_lossy()
+        .into_owned();
+
+    for secret in secrets.iter().filter(|s| !s.is_empty()) {
+        line = line.replace(secret, "****");
+    }
+
+    line
+}
+
 pub async fn spawn_command(cmd: &mut Command, hint: &str) -> Result<(), anyhow::Error> {
     let status = cmd.spawn()?.wait().await?;
 
@@ -0,0 +1,229 @@
+use super::{utils::dry_run, Config};
+use reqwest::header::{ACCEPT, AUTHORIZATION, USER_AGENT};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, io::ErrorKind};
+use tokio::fs;
+
+const GITHUB_API: &str = "https://api.github.com";
+const LATEST: &str = "latest";
+const MAX_PAGES: usize = 10;
+
+#[derive(Deserialize, Serialize, Default)]
+struct Lock(BTreeMap<String, LockEntry>);
+
+#[derive(Deserialize, Serialize, Clone)]
+struct LockEntry {
+    requested: String,
+    resolved: String,
+    // 回滚后固定的版本，配置中的版本变化后失效
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rollback: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    draft: bool,
+    prerelease: bool,
+}
+
+// 精确版本（semver或无法解析为范围的版本号）直接返回，"latest"或semver范围则通过GitHub releases解析，并记录在lock文件中
+pub async fn resolve(
+    config: &Config,
+    tool: &str,
+    repo: Option<&str>,
+    requested: &str,
+) -> Result<String, anyhow::Error> {
+    let requested = requested.trim();
+    let mut lock = Lock::load(config).await?;
+    let entry = lock.0.get(tool).filter(|e| e.requested == requested);
+
+    if let Some(rollback) = entry.and_then(|e| e.rollback.as_ref()) {
+        tracing::warn!(
+            "{}已回滚至{}，修改配置中的版本{}后才会再次升级",
+            tool,
+            rollback,
+            requested
+        );
+        return Ok(rollback.clone());
+    }
+
+    if let Some(exact) = exact(requested) {
+        return Ok(exact);
+    }
+
+    if let Some(entry) = entry {
+        tracing::info!("{}版本{}已锁定为：{}", tool, requested, entry.resolved);
+        return Ok(entry.resolved.clone());
+    }
+
+    let req = match requested {
+        LATEST => VersionReq::STAR,
+        _ => VersionReq::parse(requested)?,
+    };
+
+    let repo = repo.ok_or(anyhow::anyhow!(
+        "{}未配置repo，无法解析版本：{}",
+        tool,
+        requested
+    ))?;
+
+    tracing::info!("正在从GitHub解析{}版本：{}", tool, requested);
+    let resolved = latest_matching(repo, &req)
+        .await?
+        .ok_or(anyhow::anyhow!("{}没有符合{}的发布版本！", repo, requested))?
+        .to_string();
+    tracing::info!("{}版本{}解析为：{}", tool, requested, resolved);
+
+    lock.0.insert(
+        tool.into(),
+        LockEntry {
+            requested: requested.into(),
+            resolved: resolved.clone(),
+            rollback: None,
+        },
+    );
+
+    if config.dry_run {
+        dry_run(format!(
+            "写入{}：{} = {}",
+            config.lock_file.display(),
+            tool,
+            resolved
+        ));
+    } else {
+        lock.save(config).await?;
+    }
+
+    Ok(resolved)
+}
+
+// 记录回滚后的版本，避免下次upgrade或deploy按配置再次升级
+pub async fn pin_rollback(
+    config: &Config,
+    tool: &str,
+    requested: &str,
+    version: &str,
+) -> Result<(), anyhow::Error> {
+    let requested = requested.trim();
+
+    if config.dry_run {
+        dry_run(format!(
+            "写入{}：{}回滚至{}",
+            config.lock_file.display(),
+            tool,
+            version
+        ));
+        return Ok(());
+    }
+
+    let mut lock = Lock::load(config).await?;
+    let resolved = lock
+        .0
+        .get(tool)
+        .filter(|e| e.requested == requested)
+        .map(|e| e.resolved.clone())
+        .or(exact(requested))
+        .unwrap_or(requested.into());
+
+    lock.0.insert(
+        tool.into(),
+        LockEntry {
+            requested: requested.into(),
+            resolved,
+            rollback: Some(version.into()),
+        },
+    );
+    lock.save(config).await
+}
+
+// 非semver的版本号（例如minio的RELEASE.2024-10-13T13-34-11Z）也按原样视为精确版本
+fn exact(requested: &str) -> Option<String> {
+    let version = requested.trim_start_matches('v');
+
+    if Version::parse(version).is_ok() {
+        Some(version.into())
+    } else if requested != LATEST && VersionReq::parse(requested).is_err() {
+        Some(requested.into())
+    } else {
+        None
+    }
+}
+
+// 发布列表按时间倒序，取第一页有匹配的版本中最大的一个
+async fn latest_matching(repo: &str, req: &VersionReq) -> Result<Option<Version>, anyhow::Error> {
+    let base = env::var("GITOPS_GITHUB_API").unwrap_or(GITHUB_API.into());
+    let client = reqwest::Client::new();
+
+    for page in 1..=MAX_PAGES {
+        let url = format!(
+            "{}/repos/{}/releases?per_page=100&page={}",
+            base.trim_end_matches('/'),
+            repo,
+            page
+        );
+        tracing::info!("正在GET：{}", url);
+
+        let mut request = client
+            .get(url)
+            .header(USER_AGENT, env!("CARGO_PKG_NAME"))
+            .header(ACCEPT, "application/vnd.github+json");
+
+        if let Ok(token) = env::var("GITHUB_TOKEN") {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let releases: Vec<GithubRelease> =
+            serde_json::from_slice(&request.send().await?.error_for_status()?.bytes().await?)?;
+
+        if releases.is_empty() {
+            break;
+        }
+
+        let found = releases
+            .iter()
+            .filter(|r| !r.draft && !r.prerelease)
+            .filter_map(|r| Version::parse(r.tag_name.trim_start_matches('v')).ok())
+            .filter(|v| req.matches(v))
+            .max();
+
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+
+    Ok(None)
+}
+
+impl Lock {
+    async fn load(config: &Config) -> Result<Self, anyhow::Error> {
+        match fs::read_to_string(&config.lock_file).await {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Default::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save(&self, config: &Config) -> Result<(), anyhow::Error> {
+        tracing::info!("正在保存：{}", config.lock_file.display());
+        Ok(fs::write(&config.lock_file, toml::to_string_pretty(self)?).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_versions_skip_resolution() {
+        assert_eq!(exact("v0.136.0").as_deref(), Some("0.136.0"));
+        assert_eq!(
+            exact("RELEASE.2024-10-13T13-34-11Z").as_deref(),
+            Some("RELEASE.2024-10-13T13-34-11Z")
+        );
+        assert_eq!(exact("latest"), None);
+        assert_eq!(exact("^1.80"), None);
+        assert_eq!(exact("~2.8"), None);
+    }
+}